@@ -1,9 +1,29 @@
 fn main() {
     use cc::Build;
+    use std::env;
 
-    Build::new()
-        .file("./src/fops.c")
-        .compile("low");
+    println!("cargo:rustc-check-cfg=cfg(wrcap_fileno_swap)");
+
+    // fops.c writes glibc's private `_fileno` field, other libcs use dup2 only
+    let glibc = env::var("CARGO_CFG_TARGET_ENV").is_ok_and(|env| env == "gnu")
+        && env::var("CARGO_CFG_TARGET_OS").is_ok_and(|os| os == "linux");
+
+    if glibc && env::var_os("CARGO_FEATURE_PORTABLE").is_none() {
+        Build::new()
+            .file("./src/fops.c")
+            .compile("low");
+
+        println!("cargo:rustc-cfg=wrcap_fileno_swap");
+    }
+
+    // std::cout and friends, see src/cxx.rs
+    if env::var_os("CARGO_FEATURE_CXX").is_some() {
+        Build::new()
+            .cpp(true)
+            .file("./src/iostream.cpp")
+            .compile("wrcap_cxx");
+    }
 
     println!("cargo:rerun-if-changed=src/fops.c");
+    println!("cargo:rerun-if-changed=src/iostream.cpp");
 }
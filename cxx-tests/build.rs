@@ -0,0 +1,8 @@
+fn main() {
+    cc::Build::new()
+        .cpp(true)
+        .file("./src/print.cpp")
+        .compile("print");
+
+    println!("cargo:rerun-if-changed=src/print.cpp");
+}
@@ -0,0 +1,32 @@
+//! Tests of wrcap's `cxx` feature. The C++ side lives in print.cpp, so it is
+//! not part of the wrcap library itself.
+
+use std::ffi::CStr;
+
+use wrcap::{Stream, lent_stdio};
+
+unsafe extern "C" {
+    // in print.cpp
+    fn wrcap_cxx_sync_with_stdio(sync: i32);
+    fn wrcap_cxx_print(out: *const std::ffi::c_char, err: *const std::ffi::c_char);
+}
+
+/// See `std::ios::sync_with_stdio`.
+pub fn sync_with_stdio(sync: bool) {
+    unsafe { wrcap_cxx_sync_with_stdio(sync.into()) };
+}
+
+/// Writes `out` to `std::cout` and `err` to both `std::cerr` and `std::clog`
+/// inside a capture of stdout and stderr, and returns what each one received.
+pub fn capture_print(out: &CStr, err: &CStr) -> (Vec<u8>, Vec<u8>) {
+    let (_, tagged) = lent_stdio()
+        .unwrap()
+        .capture_tagged(|| unsafe { wrcap_cxx_print(out.as_ptr(), err.as_ptr()) })
+        .unwrap();
+
+    let of = |stream| {
+        let chunks = tagged.iter().filter(move |(s, _)| *s == stream);
+        chunks.flat_map(|(_, bytes)| bytes.clone()).collect()
+    };
+    (of(Stream::Stdout), of(Stream::Stderr))
+}
@@ -0,0 +1,13 @@
+#include <iostream>
+
+// drives the iostreams for the tests, see lib.rs
+
+extern "C" void wrcap_cxx_sync_with_stdio(int sync) {
+  std::ios::sync_with_stdio(sync != 0);
+}
+
+extern "C" void wrcap_cxx_print(const char* out, const char* err) {
+  std::cout << out;
+  std::cerr << err;
+  std::clog << err;
+}
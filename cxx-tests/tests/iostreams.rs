@@ -0,0 +1,8 @@
+use wrcap_cxx_tests::capture_print;
+
+#[test]
+fn capture_iostreams() {
+    let (out, err) = capture_print(c"cout\n", c"cerr\n");
+    assert_eq!(out, b"cout\n");
+    assert_eq!(err, b"cerr\ncerr\n");
+}
@@ -0,0 +1,14 @@
+//! Its own test binary, since `sync_with_stdio(false)` holds for the rest of
+//! the process and must come before any iostream output.
+
+use wrcap_cxx_tests::{capture_print, sync_with_stdio};
+
+#[test]
+fn capture_unsynced_iostreams() {
+    // std::cout now has its own buffer on fd 1
+    sync_with_stdio(false);
+
+    let (out, err) = capture_print(c"cout\n", c"cerr\n");
+    assert_eq!(out, b"cout\n");
+    assert_eq!(err, b"cerr\ncerr\n");
+}
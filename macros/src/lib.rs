@@ -0,0 +1,51 @@
+use proc_macro::{Delimiter, Group, Span, TokenStream, TokenTree};
+
+/// Runs a test function with C-level stdout and stderr captured.
+///
+/// Inside the function, `wrcap::test::stdout()` and `wrcap::test::stderr()`
+/// return what was printed so far. Use it together with `#[test]`.
+#[proc_macro_attribute]
+pub fn capture(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if let Some(tt) = attr.into_iter().next() {
+        return compile_error("#[capture] takes no arguments", tt.span());
+    }
+
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+
+    let Some(TokenTree::Group(body)) = tokens.last_mut() else {
+        return compile_error("#[capture] expects a function", Span::call_site());
+    };
+    if body.delimiter() != Delimiter::Brace {
+        return compile_error("#[capture] expects a function", body.span());
+    }
+
+    // { ::wrcap::test::run(|| { body }) }
+    let mut closure: TokenStream = "||".parse().unwrap();
+    closure.extend([TokenTree::Group(body.clone())]);
+
+    let mut call: TokenStream = "::wrcap::test::run".parse().unwrap();
+    call.extend([TokenTree::Group(Group::new(
+        Delimiter::Parenthesis,
+        closure,
+    ))]);
+
+    let mut wrapped = Group::new(Delimiter::Brace, call);
+    wrapped.set_span(body.span());
+    *body = wrapped;
+
+    tokens.into_iter().collect()
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let error: TokenStream = format!("::core::compile_error!({message:?});")
+        .parse()
+        .unwrap();
+
+    error
+        .into_iter()
+        .map(|mut tt| {
+            tt.set_span(span);
+            tt
+        })
+        .collect()
+}
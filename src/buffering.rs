@@ -0,0 +1,147 @@
+use std::{
+    io,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{CaptureError, Result};
+
+#[cfg(wrcap_fileno_swap)]
+unsafe extern "C" {
+    // in fops.c
+    fn is_unbuffered(file: *mut nix::libc::FILE) -> nix::libc::c_int;
+}
+
+unsafe extern "C" {
+    // in libc (stdio_ext.h)
+    fn __flbf(file: *mut nix::libc::FILE) -> nix::libc::c_int;
+    fn __fbufsize(file: *mut nix::libc::FILE) -> nix::libc::size_t;
+}
+
+// the size libc would pick for a buffer it has not allocated yet
+const DEFAULT_SIZE: usize = 8192;
+
+/// Buffering mode of a lent file during a capture, see `setvbuf(3)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffering {
+    Unbuffered,
+    /// Line buffered, with a buffer of the given size.
+    Line(usize),
+    /// Fully buffered, with a buffer of the given size.
+    Full(usize),
+}
+
+/// The buffering a file had before a capture changed it.
+pub(crate) struct Saved {
+    original: Buffering,
+    // installed in the file until the original buffering is restored
+    buffer: Option<Box<[u8]>>,
+}
+
+/// Reads the current buffering mode of `file`.
+pub(crate) unsafe fn current(file: *mut nix::libc::FILE) -> Buffering {
+    let size = unsafe { __fbufsize(file) };
+
+    #[cfg(wrcap_fileno_swap)]
+    let unbuffered = unsafe { is_unbuffered(file) } != 0;
+    // musl reports a zero sized buffer for unbuffered files, glibc a single byte
+    #[cfg(not(wrcap_fileno_swap))]
+    let unbuffered = size <= 1;
+
+    if unbuffered {
+        Buffering::Unbuffered
+    } else if size == 0 {
+        // no buffer yet, libc picks the mode on first use
+        if unsafe { nix::libc::isatty(nix::libc::fileno(file)) } == 1 {
+            Buffering::Line(DEFAULT_SIZE)
+        } else {
+            Buffering::Full(DEFAULT_SIZE)
+        }
+    } else if unsafe { __flbf(file) } != 0 {
+        Buffering::Line(size)
+    } else {
+        Buffering::Full(size)
+    }
+}
+
+/// Switches `file` from `original`, read with [`current`] before the file was
+/// redirected, to `buffering`. The file must have been flushed.
+pub(crate) unsafe fn apply(
+    file: *mut nix::libc::FILE,
+    original: Buffering,
+    buffering: Buffering,
+) -> Result<Saved> {
+    let mut buffer = match buffering {
+        Buffering::Unbuffered => None,
+        Buffering::Line(size) | Buffering::Full(size) => Some(vec![0; size].into_boxed_slice()),
+    };
+    let ptr = buffer
+        .as_mut()
+        .map_or(std::ptr::null_mut(), |b| b.as_mut_ptr());
+
+    unsafe { set(file, buffering, ptr) }.map_err(CaptureError::Install)?;
+
+    Ok(Saved { original, buffer })
+}
+
+/// Puts back the buffering saved by [`apply`]. The file must have been flushed.
+pub(crate) unsafe fn restore(file: *mut nix::libc::FILE, saved: Saved) -> Result<()> {
+    let ptr = match saved.original {
+        Buffering::Unbuffered => std::ptr::null_mut(),
+        Buffering::Line(size) | Buffering::Full(size) => restore_buffer(file, size),
+    };
+
+    unsafe { set(file, saved.original, ptr) }.map_err(CaptureError::Restore)?;
+
+    // the file no longer points into saved.buffer
+    drop(saved.buffer);
+
+    Ok(())
+}
+
+unsafe fn set(file: *mut nix::libc::FILE, buffering: Buffering, ptr: *mut u8) -> io::Result<()> {
+    let (mode, size) = match buffering {
+        Buffering::Unbuffered => (nix::libc::_IONBF, 0),
+        Buffering::Line(size) => (nix::libc::_IOLBF, size),
+        Buffering::Full(size) => (nix::libc::_IOFBF, size),
+    };
+
+    if unsafe { nix::libc::setvbuf(file, ptr.cast(), mode, size) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Whether the buffering of `file` can be changed for a capture. Only the
+/// standard streams qualify, see [`restore_buffer`].
+pub(crate) fn restorable(file: *mut nix::libc::FILE) -> bool {
+    std_stream(file).is_some()
+}
+
+fn std_stream(file: *mut nix::libc::FILE) -> Option<usize> {
+    unsafe { [crate::stdin, crate::stdout, crate::stderr] }
+        .iter()
+        .position(|&f| f == file)
+}
+
+/// A buffer of at least `size` bytes that stays valid for the rest of the
+/// process, used to give a file its buffering back.
+///
+/// libc frees the buffer it allocated itself once `setvbuf` installs ours, and
+/// a null buffer does not make it allocate a new one. Nothing tells us when a
+/// file is closed, so this is limited to stdin, stdout and stderr, which are
+/// never closed. Each keeps one buffer, which is only replaced when a larger
+/// one is needed; the old one is leaked, as the file may still point into it.
+fn restore_buffer(file: *mut nix::libc::FILE, size: usize) -> *mut u8 {
+    static BUFFERS: Mutex<[&'static mut [u8]; 3]> = Mutex::new([&mut [], &mut [], &mut []]);
+
+    let stream = std_stream(file).expect("buffering changed for a non-standard stream");
+
+    let mut buffers = BUFFERS.lock().unwrap_or_else(PoisonError::into_inner);
+    let buffer = &mut buffers[stream];
+    if buffer.len() < size {
+        *buffer = Vec::leak(vec![0; size]);
+    }
+
+    buffer.as_mut_ptr()
+}
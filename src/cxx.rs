@@ -0,0 +1,31 @@
+//! Redirection of C++ iostreams, compiled from iostream.cpp.
+//!
+//! `std::cout` does not necessarily write through `stdout`: without
+//! `sync_with_stdio` it has its own buffer on fd 1. While a capture runs,
+//! `std::cout` (for `stdout`) or `std::cerr` and `std::clog` (for `stderr`)
+//! write into the lent file instead.
+
+use std::{ffi::c_void, ptr::NonNull};
+
+unsafe extern "C" {
+    // in iostream.cpp
+    fn wrcap_cxx_install(file: *mut nix::libc::FILE) -> *mut c_void;
+    fn wrcap_cxx_uninstall(state: *mut c_void);
+}
+
+/// Puts the original stream buffers back when dropped.
+pub(crate) struct Redirected(NonNull<c_void>);
+
+/// Flushes the iostreams of `file` and points them at it. Returns `None` if
+/// `file` is not `stdout` or `stderr`.
+///
+/// Other threads must not use the iostreams while they are switched.
+pub(crate) unsafe fn install(file: *mut nix::libc::FILE) -> Option<Redirected> {
+    NonNull::new(unsafe { wrcap_cxx_install(file) }).map(Redirected)
+}
+
+impl Drop for Redirected {
+    fn drop(&mut self) {
+        unsafe { wrcap_cxx_uninstall(self.0.as_ptr()) };
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fmt, io, string::FromUtf8Error};
+
+pub type Result<T, E = CaptureError> = std::result::Result<T, E>;
+
+/// Errors returned by lending and capturing.
+///
+/// Lending never fails because of poisoning: the stream locks guard no data,
+/// so a capture that panicked does not affect later ones.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CaptureError {
+    /// The stream is lent to another capture and did not become free in time.
+    Busy,
+    /// Creating the pipe (or other capture target) failed.
+    Pipe(io::Error),
+    /// Flushing the lent file before or after the capture failed.
+    Flush(io::Error),
+    /// Redirecting the file to the capture target failed.
+    Install(io::Error),
+    /// Putting the original descriptor back failed.
+    Restore(io::Error),
+    /// Reading the captured output or writing it to a sink failed.
+    Io(io::Error),
+    /// The captured output is not valid UTF-8.
+    Utf8(FromUtf8Error),
+    /// The output exceeded the limit set with
+    /// [`LentFile::limit`](crate::LentFile::limit) and
+    /// [`Overflow::Error`](crate::Overflow::Error).
+    Overflow { limit: usize, dropped: u64 },
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Busy => write!(f, "stream is lent to another capture"),
+            CaptureError::Pipe(e) => write!(f, "failed to create capture pipe: {e}"),
+            CaptureError::Flush(e) => write!(f, "failed to flush lent file: {e}"),
+            CaptureError::Install(e) => write!(f, "failed to redirect lent file: {e}"),
+            CaptureError::Restore(e) => write!(f, "failed to restore original descriptor: {e}"),
+            CaptureError::Io(e) => write!(f, "failed to transfer captured output: {e}"),
+            CaptureError::Utf8(e) => write!(f, "captured output is not UTF-8: {e}"),
+            CaptureError::Overflow { limit, dropped } => write!(
+                f,
+                "captured output exceeded {limit} bytes, {dropped} bytes dropped"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CaptureError::Pipe(e)
+            | CaptureError::Flush(e)
+            | CaptureError::Install(e)
+            | CaptureError::Restore(e)
+            | CaptureError::Io(e) => Some(e),
+            CaptureError::Utf8(e) => Some(e),
+            CaptureError::Busy | CaptureError::Overflow { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for CaptureError {
+    fn from(e: io::Error) -> Self {
+        CaptureError::Io(e)
+    }
+}
+
+impl From<FromUtf8Error> for CaptureError {
+    fn from(e: FromUtf8Error) -> Self {
+        CaptureError::Utf8(e)
+    }
+}
+
+impl From<CaptureError> for io::Error {
+    fn from(e: CaptureError) -> Self {
+        match e {
+            CaptureError::Pipe(e)
+            | CaptureError::Flush(e)
+            | CaptureError::Install(e)
+            | CaptureError::Restore(e)
+            | CaptureError::Io(e) => e,
+            CaptureError::Utf8(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            CaptureError::Busy => io::Error::new(io::ErrorKind::WouldBlock, e),
+            CaptureError::Overflow { .. } => io::Error::new(io::ErrorKind::FileTooLarge, e),
+        }
+    }
+}
@@ -1,5 +1,9 @@
 #include <stdio.h>
 
+#ifndef _IO_UNBUFFERED
+#define _IO_UNBUFFERED 0x0002
+#endif
+
 int swap_fd(FILE* file, int fd) {
   int old_fd = fileno(file);
   file->_fileno = fd;
@@ -7,3 +11,6 @@ int swap_fd(FILE* file, int fd) {
   return old_fd;
 }
 
+int is_unbuffered(FILE* file) {
+  return (file->_flags & _IO_UNBUFFERED) != 0;
+}
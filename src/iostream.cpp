@@ -0,0 +1,82 @@
+#include <cstdio>
+#include <iostream>
+#include <streambuf>
+
+namespace {
+
+// writes straight into a FILE*, so the iostream follows the capture of that
+// file in either redirect mode and keeps its order with C stdio output
+class file_buf : public std::streambuf {
+ public:
+  explicit file_buf(FILE* file) : file_(file) {}
+
+ protected:
+  int_type overflow(int_type c) override {
+    if (traits_type::eq_int_type(c, traits_type::eof())) {
+      return traits_type::not_eof(c);
+    }
+    return std::fputc(c, file_) == EOF ? traits_type::eof() : c;
+  }
+
+  std::streamsize xsputn(const char* s, std::streamsize n) override {
+    return std::fwrite(s, 1, n, file_);
+  }
+
+  int sync() override { return std::fflush(file_) == 0 ? 0 : -1; }
+
+ private:
+  FILE* file_;
+};
+
+struct redirected {
+  explicit redirected(FILE* file) : buf(file) {}
+
+  file_buf buf;
+  std::ostream* streams[2] = {};
+  std::streambuf* saved[2] = {};
+};
+
+}  // namespace
+
+extern "C" {
+
+// points the iostreams of file (cout for stdout, cerr and clog for stderr) at
+// it, after flushing what they buffered. returns NULL for other files.
+void* wrcap_cxx_install(FILE* file) {
+  redirected* r = new redirected(file);
+
+  if (file == stdout) {
+    r->streams[0] = &std::cout;
+  } else if (file == stderr) {
+    r->streams[0] = &std::cerr;
+    r->streams[1] = &std::clog;
+  } else {
+    delete r;
+    return nullptr;
+  }
+
+  for (int i = 0; i < 2; i++) {
+    if (r->streams[i]) {
+      r->streams[i]->flush();
+      r->saved[i] = r->streams[i]->rdbuf(&r->buf);
+    }
+  }
+
+  return r;
+}
+
+void wrcap_cxx_uninstall(void* state) {
+  redirected* r = static_cast<redirected*>(state);
+
+  // in reverse, so nested captures unwind in order
+  for (int i = 1; i >= 0; i--) {
+    if (r->streams[i]) {
+      r->streams[i]->flush();
+      r->streams[i]->rdbuf(r->saved[i]);
+    }
+  }
+
+  delete r;
+}
+
+}  // extern "C"
@@ -1,109 +1,883 @@
-#![feature(anonymous_pipe)]
+mod buffering;
+#[cfg(feature = "cxx")]
+mod cxx;
+mod error;
+mod limit;
+mod lines;
+mod registry;
+mod stdio;
+pub mod test;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+// lets #[capture] refer to ::wrcap inside this crate's own tests
+extern crate self as wrcap;
 
 use std::{
-    io::{self, Read},
-    os::fd::{FromRawFd, IntoRawFd, OwnedFd},
-    pipe::{PipeReader, pipe},
-    sync::{Mutex, MutexGuard, PoisonError},
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, PipeReader, Read, Seek, Write, pipe},
+    marker::PhantomData,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    path::Path,
+    process::{Command, ExitStatus},
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+use registry::Lease;
+
+pub use buffering::Buffering;
+pub use error::{CaptureError, Result};
+pub use limit::Overflow;
+pub use lines::CapturedLine;
+pub use stdio::{Chunks, LentStdio, Stream, lent_stdio};
+#[cfg(feature = "macros")]
+pub use wrcap_macros::capture;
+
+#[cfg(wrcap_fileno_swap)]
 unsafe extern "C" {
     // in fops.c
     fn swap_fd(file: *mut nix::libc::FILE, fd: nix::libc::c_int) -> nix::libc::c_int;
+}
 
+unsafe extern "C" {
     // in libc
     fn flockfile(file: *mut nix::libc::FILE);
+    fn ftrylockfile(file: *mut nix::libc::FILE) -> nix::libc::c_int;
     fn funlockfile(file: *mut nix::libc::FILE);
+    fn __fpurge(file: *mut nix::libc::FILE);
 
+    static mut stdin: *mut nix::libc::FILE;
     static mut stdout: *mut nix::libc::FILE;
     static mut stderr: *mut nix::libc::FILE;
 }
 
+/// How a capture redirects the lent file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redirect {
+    /// Swap the descriptor inside the `FILE*` only. Writes to the underlying
+    /// fd (`println!`, `write(1, ..)`) are not captured.
+    ///
+    /// This needs glibc's `FILE` layout. With the `portable` feature or on
+    /// other libcs it behaves like [`Redirect::Descriptor`].
+    #[default]
+    Stream,
+    /// `dup2` the capture onto the file's descriptor, so Rust std output and
+    /// raw writes to that fd are captured together with the C stdio output.
+    Descriptor,
+}
+
+/// What happens to output from other threads while a capture runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ThreadPolicy {
+    /// C stdio calls of other threads on the stream block until the lent
+    /// file is dropped. Rust std output and raw `write(2)` calls are not
+    /// blocked, and with [`Redirect::Descriptor`] they end up in the capture.
+    ///
+    /// C stdio output of threads the closure waits for is therefore not
+    /// allowed.
+    #[default]
+    Serialize,
+    /// Like [`ThreadPolicy::Serialize`], and for `stdout` and `stderr` also
+    /// holds Rust's lock on `std::io::stdout()` or `std::io::stderr()` while
+    /// the capture runs, so `println!` of other threads blocks as well.
+    ///
+    /// The Rust lock is taken after the stream is lent. Code that lends the
+    /// stream while holding the Rust lock can therefore deadlock against a
+    /// capture with this policy. The closure must not wait for threads
+    /// printing through Rust std, and a [`LentFile::capture_with`] sink must
+    /// not lock the same Rust stream.
+    SerializeWithStd,
+    /// Writes of other threads to the descriptor (Rust std output, raw
+    /// `write(2)`) keep going to the original fd. Their C stdio calls still
+    /// block, since the `FILE*` itself is shared. Captures fail with
+    /// [`CaptureError::Install`] unless the file uses [`Redirect::Stream`]
+    /// on glibc.
+    RouteToOriginal,
+    /// Nothing is blocked. Everything written to the stream, or to its
+    /// descriptor with [`Redirect::Descriptor`], by any thread is captured.
+    IncludeAll,
+}
+
+/// A stream locked for capturing.
+///
+/// The thread holding it can lend the same stream again. A nested capture
+/// redirects to its own target and puts the outer one back when it is done.
+///
+/// # C++ iostreams
+///
+/// With the `cxx` feature, captures of `stdout` also switch the stream buffer
+/// of `std::cout`, and captures of `stderr` those of `std::cerr` and
+/// `std::clog`. This writes to the global stream objects, so other threads
+/// must not use them while a capture starts or ends, whatever the
+/// [`ThreadPolicy`]. In C++ that would be a data race.
 pub struct LentFile {
     file: *mut nix::libc::FILE,
+    redirect: Redirect,
+    buffering: Option<Buffering>,
+    policy: ThreadPolicy,
+    limit: Option<(usize, Overflow)>,
+    dropped: Cell<u64>,
 
     #[allow(dead_code)]
-    guard: MutexGuard<'static, ()>,
+    lease: Lease,
 }
 
-pub fn lent_stdout() -> Result<LentFile, PoisonError<MutexGuard<'static, ()>>> {
-    static MUTEX: Mutex<()> = Mutex::new(());
-    let guard = MUTEX.lock()?;
+pub fn lent_stdin() -> Result<LentFile> {
+    unsafe { lent_file(stdin) } // SAFETY: stdin lives as long as the process
+}
 
-    unsafe { flockfile(stdout) };
+pub fn lent_stdout() -> Result<LentFile> {
+    unsafe { lent_file(stdout) } // SAFETY: stdout lives as long as the process
+}
 
-    Ok(LentFile {
-        file: unsafe { stdout }, // SAFETY: lock is held
-        guard,
-    })
+pub fn lent_stderr() -> Result<LentFile> {
+    unsafe { lent_file(stderr) } // SAFETY: stderr lives as long as the process
 }
 
-pub fn lent_stderr() -> Result<LentFile, PoisonError<MutexGuard<'static, ()>>> {
-    static MUTEX: Mutex<()> = Mutex::new(());
-    let guard = MUTEX.lock()?;
+/// Like [`lent_stdout`], but fails with [`CaptureError::Busy`] instead of
+/// waiting when stdout is lent to another capture.
+pub fn try_lent_stdout() -> Result<LentFile> {
+    unsafe { lent_file_timeout(stdout, Duration::ZERO) } // SAFETY: see lent_stdout
+}
+
+pub fn try_lent_stderr() -> Result<LentFile> {
+    unsafe { lent_file_timeout(stderr, Duration::ZERO) } // SAFETY: see lent_stderr
+}
+
+/// Like [`lent_stdout`], but fails with [`CaptureError::Busy`] if stdout is
+/// not free within `timeout`.
+pub fn lent_stdout_timeout(timeout: Duration) -> Result<LentFile> {
+    unsafe { lent_file_timeout(stdout, timeout) } // SAFETY: see lent_stdout
+}
+
+pub fn lent_stderr_timeout(timeout: Duration) -> Result<LentFile> {
+    unsafe { lent_file_timeout(stderr, timeout) } // SAFETY: see lent_stderr
+}
 
-    unsafe { flockfile(stderr) };
+unsafe fn lent_file_timeout(file: *mut nix::libc::FILE, timeout: Duration) -> Result<LentFile> {
+    // a timeout too large for an Instant never runs out
+    let deadline = Instant::now().checked_add(timeout);
+    let lease = registry::acquire_timeout(file, timeout).ok_or(CaptureError::Busy)?;
+
+    // other threads hold the stdio lock only for the duration of a call
+    while unsafe { ftrylockfile(file) } != 0 {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(CaptureError::Busy);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
 
     Ok(LentFile {
-        file: unsafe { stderr }, // SAFETY: lock is held
-        guard,
+        file,
+        redirect: Redirect::default(),
+        buffering: None,
+        policy: ThreadPolicy::default(),
+        limit: None,
+        dropped: Cell::new(0),
+        lease,
     })
 }
 
+/// Lends an arbitrary stream, e.g. a log file opened by a C library.
+///
+/// # Safety
+///
+/// `file` must be an open stream that is not closed while the returned
+/// [`LentFile`] is alive.
+pub unsafe fn lent_file(file: *mut nix::libc::FILE) -> Result<LentFile> {
+    let lease = registry::acquire(file);
+
+    Ok(unsafe { LentFile::from_lease(file, lease) })
+}
+
 impl Drop for LentFile {
     fn drop(&mut self) {
-        unsafe { funlockfile(self.file) };
+        if self.policy != ThreadPolicy::IncludeAll {
+            unsafe { funlockfile(self.file) };
+        }
+    }
+}
+
+/// A capture installed into a file. Puts the old descriptor back when it is
+/// restored or dropped.
+struct Swap<'a> {
+    id: Option<u64>,
+    file: *mut nix::libc::FILE,
+    _lent: PhantomData<&'a LentFile>,
+}
+
+/// Rust's own lock on its stdout or stderr handle.
+enum RustLock {
+    Stdout(#[allow(dead_code)] io::StdoutLock<'static>),
+    Stderr(#[allow(dead_code)] io::StderrLock<'static>),
+}
+
+/// What a capture puts back when it ends.
+struct Installed {
+    id: u64,
+    file: *mut nix::libc::FILE,
+    redirect: Redirect,
+    old_fd: OwnedFd,
+    buffering: Option<buffering::Saved>,
+    #[cfg(feature = "cxx")]
+    cxx: Option<cxx::Redirected>,
+    // ended while a capture inside it was still running
+    abandoned: bool,
+
+    // dropped after the descriptor is restored
+    #[allow(dead_code)]
+    rust_lock: Option<RustLock>,
+}
+
+thread_local! {
+    // captures of this thread, innermost last. only the thread holding the
+    // lease of a file installs into it
+    static INSTALLED: RefCell<Vec<Installed>> = const { RefCell::new(Vec::new()) };
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+impl Swap<'_> {
+    /// The descriptor the file wrote to before the capture.
+    fn original(&self) -> io::Result<OwnedFd> {
+        INSTALLED.with_borrow(|installed| {
+            let installed = installed.iter().find(|i| Some(i.id) == self.id);
+            installed.expect("capture not installed").old_fd.try_clone()
+        })
+    }
+
+    fn restore(mut self) -> Result<()> {
+        self.end()
+    }
+
+    /// Restores the file if this is the innermost capture in it. Otherwise the
+    /// capture is restored once the captures inside it have ended.
+    fn end(&mut self) -> Result<()> {
+        let Some(id) = self.id.take() else {
+            return Ok(());
+        };
+
+        let ended = INSTALLED.with_borrow_mut(|installed| {
+            let pos = installed.iter().position(|i| i.id == id);
+            let pos = pos.expect("capture not installed");
+            if installed[pos + 1..].iter().any(|i| i.file == self.file) {
+                installed[pos].abandoned = true;
+                return None;
+            }
+
+            let mut ended = vec![installed.remove(pos)];
+            // outer captures that already ended were waiting for this one
+            while let Some(pos) = installed.iter().rposition(|i| i.file == self.file) {
+                if !installed[pos].abandoned {
+                    break;
+                }
+                ended.push(installed.remove(pos));
+            }
+
+            Some(ended)
+        });
+
+        let Some(ended) = ended else {
+            return Err(CaptureError::Restore(io::Error::other(
+                "a capture started later on the same file is still running",
+            )));
+        };
+
+        // restore all of them, and report the first error
+        ended
+            .into_iter()
+            .map(Installed::restore)
+            .fold(Ok(()), Result::and)
+    }
+}
+
+impl Drop for Swap<'_> {
+    fn drop(&mut self) {
+        // only reached without restore() when unwinding out of the closure
+        let _ = self.end();
+    }
+}
+
+impl Installed {
+    fn restore(mut self) -> Result<()> {
+        #[cfg(feature = "cxx")]
+        drop(self.cxx.take());
+
+        // after capture, we must flush the file
+        let flushed = flush(self.file, self.redirect);
+        let restored = with_stdio_lock(self.file, || unsafe {
+            uninstall(self.file, self.redirect, self.old_fd)
+        });
+        let rebuffered = match self.buffering.take() {
+            Some(saved) => unsafe { buffering::restore(self.file, saved) },
+            None => Ok(()),
+        };
+
+        flushed.and(restored).and(rebuffered)
+    }
+}
+
+/// A running capture started by [`LentFile::begin_capture`] or
+/// [`LentFile::begin_capture_into`].
+///
+/// Dropping the session without calling [`CaptureSession::finish`] restores
+/// the file as well, but errors are ignored and the output is discarded.
+///
+/// Sessions on the same file must end in reverse order. Finishing one while a
+/// session started after it is still running fails with
+/// [`CaptureError::Restore`], and the file is restored once the later session
+/// ends.
+pub struct CaptureSession<'a, T = ()> {
+    swap: Swap<'a>,
+    output: T,
+}
+
+impl<T> CaptureSession<'_, T> {
+    /// Flushes the file, puts the original descriptor back and returns the
+    /// output: the pipe reader for [`LentFile::begin_capture`].
+    pub fn finish(self) -> Result<T> {
+        self.swap.restore()?;
+
+        Ok(self.output)
     }
 }
 
 impl LentFile {
+    /// Locks `file` for the current thread. The caller must hold its lease.
+    unsafe fn from_lease(file: *mut nix::libc::FILE, lease: Lease) -> LentFile {
+        unsafe { flockfile(file) };
+
+        LentFile {
+            file,
+            redirect: Redirect::default(),
+            buffering: None,
+            policy: ThreadPolicy::default(),
+            limit: None,
+            dropped: Cell::new(0),
+            lease,
+        }
+    }
+
+    pub fn redirect(mut self, redirect: Redirect) -> Self {
+        self.redirect = redirect;
+        self
+    }
+
+    /// How output of other threads is treated, see [`ThreadPolicy`].
+    pub fn thread_policy(mut self, policy: ThreadPolicy) -> Self {
+        let locked = self.policy != ThreadPolicy::IncludeAll;
+        let lock = policy != ThreadPolicy::IncludeAll;
+
+        match (locked, lock) {
+            (true, false) => unsafe { funlockfile(self.file) },
+            (false, true) => unsafe { flockfile(self.file) },
+            _ => {}
+        }
+
+        self.policy = policy;
+        self
+    }
+
+    /// Buffering of the file while a capture runs. The original mode is put
+    /// back afterwards. By default the file keeps its current buffering.
+    ///
+    /// Only supported for `stdin`, `stdout` and `stderr`. Captures of other
+    /// files fail with [`CaptureError::Install`].
+    pub fn buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = Some(buffering);
+        self
+    }
+
+    #[cfg(wrcap_fileno_swap)]
     unsafe fn swap_fd<FD: IntoRawFd>(&self, fd: FD) -> OwnedFd {
         let swapped = unsafe { swap_fd(self.file, fd.into_raw_fd()) };
         unsafe { OwnedFd::from_raw_fd(swapped) }
     }
 
-    fn flush(&self) -> Result<(), io::Error> {
-        if unsafe { nix::libc::fflush(self.file) } == 0 {
-            Ok(())
+    fn effective_redirect(&self) -> Redirect {
+        if cfg!(wrcap_fileno_swap) {
+            self.redirect
         } else {
-            Err(io::Error::last_os_error())
+            Redirect::Descriptor
         }
     }
 
-    pub fn capture_into<FD: IntoRawFd, F: FnOnce()>(&self, fd: FD, f: F) -> std::io::Result<()> {
-        // self.file is locked. and any other threads can't create a new LentFile.
+    fn fileno(&self) -> RawFd {
+        unsafe { nix::libc::fileno(self.file) }
+    }
+
+    /// Flushes the file and installs `fd` into it. The returned guard puts the
+    /// old descriptor back when it is restored or dropped.
+    unsafe fn install<FD: IntoRawFd>(&self, fd: FD) -> Result<Swap<'_>> {
+        let rust_lock = match (self.policy, self.fileno()) {
+            (ThreadPolicy::SerializeWithStd, nix::libc::STDOUT_FILENO) => {
+                Some(RustLock::Stdout(io::stdout().lock()))
+            }
+            (ThreadPolicy::SerializeWithStd, nix::libc::STDERR_FILENO) => {
+                Some(RustLock::Stderr(io::stderr().lock()))
+            }
+            _ => None,
+        };
+
+        if self.policy == ThreadPolicy::RouteToOriginal
+            && self.effective_redirect() == Redirect::Descriptor
+        {
+            return Err(CaptureError::Install(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "routing other threads to the original fd needs Redirect::Stream",
+            )));
+        }
+
+        if self.buffering.is_some() && !buffering::restorable(self.file) {
+            return Err(CaptureError::Install(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "buffering can only be changed for stdin, stdout and stderr",
+            )));
+        }
+
+        // iostream buffers flush into the file, before it is flushed itself
+        #[cfg(feature = "cxx")]
+        let cxx = unsafe { cxx::install(self.file) };
 
         // before install fd, we must flush the file
-        self.flush()?;
+        flush(self.file, self.effective_redirect())?;
 
-        let old_fd = unsafe { self.swap_fd(fd) };
+        // a file without a buffer yet picks its mode from its descriptor
+        let original = self
+            .buffering
+            .map(|_| unsafe { buffering::current(self.file) });
 
-        f();
+        let old_fd = with_stdio_lock(self.file, || unsafe { self.redirect_to(fd) })?;
 
-        // after capture, we must flush the file
-        self.flush()?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        INSTALLED.with_borrow_mut(|installed| {
+            installed.push(Installed {
+                id,
+                file: self.file,
+                redirect: self.effective_redirect(),
+                old_fd,
+                buffering: None,
+                #[cfg(feature = "cxx")]
+                cxx,
+                abandoned: false,
+                rust_lock,
+            })
+        });
+        let swap = Swap {
+            id: Some(id),
+            file: self.file,
+            _lent: PhantomData,
+        };
+
+        // the file was flushed before installing
+        if let (Some(original), Some(buffering)) = (original, self.buffering) {
+            let saved = unsafe { buffering::apply(self.file, original, buffering)? };
+            INSTALLED.with_borrow_mut(|installed| {
+                installed
+                    .last_mut()
+                    .expect("capture not installed")
+                    .buffering = Some(saved)
+            });
+        }
+
+        Ok(swap)
+    }
+
+    unsafe fn redirect_to<FD: IntoRawFd>(&self, fd: FD) -> Result<OwnedFd> {
+        let old_fd = match self.effective_redirect() {
+            #[cfg(wrcap_fileno_swap)]
+            Redirect::Stream => unsafe { self.swap_fd(fd) },
+            _ => {
+                let fd = unsafe { OwnedFd::from_raw_fd(fd.into_raw_fd()) };
+                let target = self.fileno();
+
+                let saved = cvt(unsafe { nix::libc::fcntl(target, nix::libc::F_DUPFD_CLOEXEC, 0) })
+                    .map_err(CaptureError::Install)?;
+                let saved = unsafe { OwnedFd::from_raw_fd(saved) };
 
-        let _swapped = unsafe { self.swap_fd(old_fd) };
+                cvt(unsafe { nix::libc::dup2(fd.as_raw_fd(), target) })
+                    .map_err(CaptureError::Install)?;
 
-        // drop _swapped(pipe writer)
+                // drop fd, the target is now the only reference we keep
+                saved
+            }
+        };
 
-        Ok(())
+        Ok(old_fd)
     }
 
-    pub fn capture<F: FnOnce()>(&self, f: F) -> std::io::Result<PipeReader> {
-        let (reader, writer) = pipe()?;
+    /// Starts capturing into `fd` until the returned session is finished or
+    /// dropped, for code that does not fit into a closure.
+    pub fn begin_capture_into<FD: IntoRawFd>(&self, fd: FD) -> Result<CaptureSession<'_>> {
+        // self.file is locked. and any other threads can't create a new LentFile.
+        let swap = unsafe { self.install(fd)? };
+
+        Ok(CaptureSession { swap, output: () })
+    }
 
-        self.capture_into(writer, f)?;
+    /// Starts capturing into a pipe, whose reader is returned by
+    /// [`CaptureSession::finish`]. Like with [`LentFile::capture`], writes
+    /// block once the pipe buffer is full.
+    pub fn begin_capture(&self) -> Result<CaptureSession<'_, PipeReader>> {
+        let (reader, writer) = pipe().map_err(CaptureError::Pipe)?;
+        let swap = unsafe { self.install(writer)? };
 
-        Ok(reader)
+        Ok(CaptureSession {
+            swap,
+            output: reader,
+        })
     }
 
-    pub fn capture_string<F: FnOnce()>(&self, f: F) -> std::io::Result<String> {
-        let mut reader = self.capture(f)?;
-        let mut string = String::new();
-        reader.read_to_string(&mut string)?;
+    /// Runs `f` with the file redirected to `fd` and returns what `f`
+    /// returned.
+    pub fn capture_into<FD: IntoRawFd, R, F: FnOnce() -> R>(&self, fd: FD, f: F) -> Result<R> {
+        let session = self.begin_capture_into(fd)?;
 
-        Ok(string)
+        let r = f();
+
+        session.finish()?;
+        Ok(r)
+    }
+
+    /// The reader is returned only after `f` finishes, so `f` blocks once it
+    /// fills the pipe buffer. Use [`LentFile::capture_bytes`] for large output.
+    pub fn capture<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, PipeReader)> {
+        let session = self.begin_capture()?;
+
+        let r = f();
+
+        Ok((r, session.finish()?))
+    }
+
+    /// Like [`LentFile::capture`], for closures returning a `Result`. An error
+    /// of `f` is returned instead of the output.
+    pub fn try_capture<T, E, F>(&self, f: F) -> Result<(T, PipeReader), E>
+    where
+        E: From<CaptureError>,
+        F: FnOnce() -> Result<T, E>,
+    {
+        transpose(self.capture(f))
+    }
+
+    /// Captures into an anonymous in-memory file, returned rewound to the
+    /// start. No pipe is involved, so output size is only limited by memory.
+    #[cfg(target_os = "linux")]
+    pub fn capture_to_memfd<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, File)> {
+        let fd = cvt(unsafe { nix::libc::memfd_create(c"wrcap".as_ptr(), nix::libc::MFD_CLOEXEC) })
+            .map_err(CaptureError::Pipe)?;
+
+        self.capture_to_file(File::from(unsafe { OwnedFd::from_raw_fd(fd) }), f)
+    }
+
+    /// Captures into the file at `path`, which is created or truncated. The
+    /// returned handle is rewound to the start.
+    pub fn capture_to_path<P: AsRef<Path>, R, F: FnOnce() -> R>(
+        &self,
+        path: P,
+        f: F,
+    ) -> Result<(R, File)> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(CaptureError::Pipe)?;
+
+        self.capture_to_file(file, f)
+    }
+
+    fn capture_to_file<R, F: FnOnce() -> R>(&self, mut file: File, f: F) -> Result<(R, File)> {
+        // the installed fd shares its offset with file
+        let target = file.try_clone().map_err(CaptureError::Pipe)?;
+
+        let r = self.capture_into(target, f)?;
+        file.rewind()?;
+
+        Ok((r, file))
+    }
+
+    /// Captures into memory while a helper thread drains the pipe, so `f` can
+    /// print more than the pipe buffer without blocking.
+    pub fn capture_bytes<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, Vec<u8>)> {
+        let mut sink = self.bounded();
+        let r = self.capture_with(&mut sink, f)?;
+
+        Ok((r, self.collect(sink)?))
+    }
+
+    /// Like [`LentFile::capture_bytes`], for closures returning a `Result`.
+    pub fn try_capture_bytes<T, E, F>(&self, f: F) -> Result<(T, Vec<u8>), E>
+    where
+        E: From<CaptureError>,
+        F: FnOnce() -> Result<T, E>,
+    {
+        transpose(self.capture_bytes(f))
+    }
+
+    /// Forwards captured output to `sink` while `f` is still running.
+    ///
+    /// Output arrives whenever libc flushes the file. If `sink` fails, the rest
+    /// of the output is discarded and the error is returned after `f` finishes.
+    pub fn capture_with<W: Write + Send, R, F: FnOnce() -> R>(&self, sink: W, f: F) -> Result<R> {
+        let (reader, writer) = pipe().map_err(CaptureError::Pipe)?;
+
+        thread::scope(|scope| {
+            let drain = scope.spawn(move || drain(reader, sink));
+
+            // the writer is dropped when capture_into returns, which ends the drain
+            let captured = self.capture_into(writer, f);
+            let drained = drain.join().expect("drain thread panicked");
+
+            let r = captured?;
+            drained?;
+            Ok(r)
+        })
+    }
+
+    /// Captures into memory and also passes every chunk through to the
+    /// descriptor the file wrote to before, e.g. the terminal.
+    pub fn capture_tee<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, Vec<u8>)> {
+        let (reader, writer) = pipe().map_err(CaptureError::Pipe)?;
+
+        let swap = unsafe { self.install(writer)? };
+        let original = swap.original();
+        let original = File::from(original.map_err(CaptureError::Install)?);
+
+        let mut sink = self.bounded();
+        let r = thread::scope(|scope| {
+            let drain = scope.spawn(|| drain(reader, Tee(&mut sink, original)));
+
+            let r = f();
+
+            // the writer is dropped on restore, which ends the drain
+            let restored = swap.restore();
+            let drained = drain.join().expect("drain thread panicked");
+
+            restored?;
+            drained?;
+            Ok::<_, CaptureError>(r)
+        })?;
+
+        Ok((r, self.collect(sink)?))
+    }
+
+    pub fn capture_string<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, String)> {
+        let (r, bytes) = self.capture_bytes(f)?;
+
+        Ok((r, String::from_utf8(bytes)?))
+    }
+
+    /// Like [`LentFile::capture_string`], for closures returning a `Result`.
+    pub fn try_capture_string<T, E, F>(&self, f: F) -> Result<(T, String), E>
+    where
+        E: From<CaptureError>,
+        F: FnOnce() -> Result<T, E>,
+    {
+        transpose(self.capture_string(f))
+    }
+
+    /// Like [`LentFile::capture_string`], but replaces invalid UTF-8 sequences
+    /// with U+FFFD instead of failing.
+    pub fn capture_string_lossy<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, String)> {
+        let (r, bytes) = self.capture_bytes(f)?;
+
+        let string = match String::from_utf8(bytes) {
+            Ok(string) => string,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        };
+        Ok((r, string))
+    }
+
+    /// Runs `cmd` and captures what it writes to the stream matching the lent
+    /// file (stderr for fd 2, stdout otherwise), together with anything the
+    /// file itself receives meanwhile.
+    ///
+    /// Children spawned by C code (e.g. `system()`) inside the other capture
+    /// methods only inherit the capture with [`Redirect::Descriptor`].
+    /// Background processes that keep the descriptor open delay the end of the
+    /// capture until they exit.
+    pub fn capture_command(&self, mut cmd: Command) -> Result<(ExitStatus, Vec<u8>)> {
+        let (status, output) = self.capture_bytes(|| {
+            // the installed capture target, in either redirect mode
+            let target = unsafe { BorrowedFd::borrow_raw(self.fileno()) }.try_clone_to_owned();
+
+            target.and_then(|target| {
+                if self.fileno() == nix::libc::STDERR_FILENO {
+                    cmd.stderr(target);
+                } else {
+                    cmd.stdout(target);
+                }
+                let status = cmd.status();
+
+                // cmd holds a copy of the write end, which would keep the drain open
+                drop(cmd);
+                status
+            })
+        })?;
+
+        Ok((status?, output))
+    }
+
+    /// Runs `f` with the file writing to `/dev/null`, e.g. to mute a noisy
+    /// library. No pipe is involved, so any amount of output is fine.
+    pub fn silence<R, F: FnOnce() -> R>(&self, f: F) -> Result<R> {
+        // opened once, every silence gets its own copy
+        static DEV_NULL: OnceLock<OwnedFd> = OnceLock::new();
+
+        let dev_null = match DEV_NULL.get() {
+            Some(fd) => fd,
+            None => {
+                let file = File::options()
+                    .write(true)
+                    .open("/dev/null")
+                    .map_err(CaptureError::Pipe)?;
+                DEV_NULL.get_or_init(|| file.into())
+            }
+        };
+        let target = dev_null.try_clone().map_err(CaptureError::Pipe)?;
+
+        self.capture_into(target, f)
+    }
+
+    /// Runs `f` with the file reading from a pipe that is filled from `input`.
+    ///
+    /// Input buffered in the file before and after the call is discarded, and
+    /// the end-of-file flag is cleared. Bytes `f` did not read are dropped.
+    pub fn feed_from<I: Read + Send, R, F: FnOnce() -> R>(&self, mut input: I, f: F) -> Result<R> {
+        let (reader, mut writer) = pipe().map_err(CaptureError::Pipe)?;
+
+        thread::scope(|scope| {
+            let feed = scope.spawn(move || match io::copy(&mut input, &mut writer) {
+                // f stopped reading and the read end is already closed
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                copied => copied.map(|_| ()),
+            });
+
+            self.discard_input();
+            let fed = self.capture_into(reader, f);
+            self.discard_input();
+
+            let copied = feed.join().expect("feed thread panicked");
+
+            let r = fed?;
+            copied?;
+            Ok(r)
+        })
+    }
+
+    fn discard_input(&self) {
+        unsafe {
+            __fpurge(self.file);
+            nix::libc::clearerr(self.file);
+        }
+    }
+}
+
+/// Writes everything to both `A` and `B`.
+struct Tee<A, B>(A, B);
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+/// Copies `reader` into `sink` until the write end is closed.
+///
+/// The pipe is read to the end even if `sink` fails, so the writer never
+/// blocks on a full pipe.
+fn drain<W: Write>(mut reader: PipeReader, mut sink: W) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    let mut result = Ok(());
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        if result.is_ok() {
+            result = sink.write_all(&buf[..n]).and_then(|()| sink.flush());
+        }
+    }
+
+    result
+}
+
+/// Runs `f` holding the stdio lock of `file`, which other threads may use in
+/// the middle of a call without [`ThreadPolicy::Serialize`].
+fn with_stdio_lock<T>(file: *mut nix::libc::FILE, f: impl FnOnce() -> T) -> T {
+    // flockfile is recursive, so this is a no-op if the lock is held
+    unsafe { flockfile(file) };
+    let t = f();
+    unsafe { funlockfile(file) };
+
+    t
+}
+
+fn flush(file: *mut nix::libc::FILE, redirect: Redirect) -> Result<()> {
+    if unsafe { nix::libc::fflush(file) } != 0 {
+        return Err(CaptureError::Flush(io::Error::last_os_error()));
+    }
+
+    // rust keeps its own buffer in front of fd 1
+    if redirect == Redirect::Descriptor
+        && unsafe { nix::libc::fileno(file) } == nix::libc::STDOUT_FILENO
+    {
+        io::stdout().flush().map_err(CaptureError::Flush)?;
+    }
+
+    Ok(())
+}
+
+unsafe fn uninstall(file: *mut nix::libc::FILE, redirect: Redirect, old_fd: OwnedFd) -> Result<()> {
+    match redirect {
+        #[cfg(wrcap_fileno_swap)]
+        Redirect::Stream => {
+            // drop _swapped(installed fd)
+            let _swapped = unsafe { OwnedFd::from_raw_fd(swap_fd(file, old_fd.into_raw_fd())) };
+        }
+        _ => {
+            // dup2 closes the installed fd; old_fd (the saved copy) is dropped
+            cvt(unsafe { nix::libc::dup2(old_fd.as_raw_fd(), nix::libc::fileno(file)) })
+                .map_err(CaptureError::Restore)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the error of a fallible closure out of a capture result.
+fn transpose<T, E: From<CaptureError>, O>(
+    captured: Result<(Result<T, E>, O)>,
+) -> Result<(T, O), E> {
+    let (r, output) = captured?;
+
+    Ok((r?, output))
+}
+
+fn cvt(ret: nix::libc::c_int) -> io::Result<nix::libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
     }
 }
 
@@ -117,12 +891,836 @@ mod tests {
     }
 
     #[test]
+    fn capture_larger_than_pipe_buffer() {
+        let line = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcde\0";
+
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .capture_string(|| unsafe {
+                for _ in 0..4096 {
+                    puts(line.as_ptr());
+                }
+            })
+            .unwrap();
+
+        assert_eq!(r.len(), 4096 * 64);
+        assert!(r.lines().all(|l| l.len() == 63));
+    }
+
+    #[test]
+    fn restore_after_panic() {
+        let lent = lent_stdout().unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lent.capture_string(|| unsafe {
+                puts(c"lost".as_ptr().cast());
+                panic!("closure panicked");
+            })
+        }));
+        assert!(result.is_err());
+
+        let (_, r) = lent
+            .capture_string(|| unsafe {
+                puts(c"after panic".as_ptr().cast());
+            })
+            .unwrap();
+
+        assert_eq!(r, "after panic\n");
+    }
+
+    #[test]
+    fn lend_after_panicking_capture() {
+        let result = std::panic::catch_unwind(|| {
+            lent_stderr()
+                .unwrap()
+                .capture_bytes(|| panic!("closure panicked"))
+        });
+        assert!(result.is_err());
+
+        let (_, r) = lent_stderr()
+            .unwrap()
+            .capture_string(|| unsafe {
+                nix::libc::fputs(c"not poisoned".as_ptr(), stderr);
+            })
+            .unwrap();
+
+        assert_eq!(r, "not poisoned");
+    }
+
+    #[test]
+    fn try_lent_when_busy() {
+        use std::time::Duration;
+
+        let held = lent_stderr().unwrap();
+
+        std::thread::spawn(|| {
+            assert!(matches!(try_lent_stderr(), Err(CaptureError::Busy)));
+
+            let start = std::time::Instant::now();
+            let timeout = Duration::from_millis(50);
+            assert!(matches!(
+                lent_stderr_timeout(timeout),
+                Err(CaptureError::Busy)
+            ));
+            assert!(start.elapsed() >= timeout);
+        })
+        .join()
+        .unwrap();
+
+        // Duration::MAX waits without a deadline
+        let waiter = std::thread::spawn(|| {
+            lent_stderr_timeout(Duration::MAX).unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(20));
+        drop(held);
+        waiter.join().unwrap();
+
+        std::thread::spawn(|| {
+            lent_stderr_timeout(Duration::from_secs(5)).unwrap();
+            lent_stderr_timeout(Duration::MAX).unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn nested_capture_on_same_thread() {
+        let (_, outer) = lent_stdout()
+            .unwrap()
+            .capture_string(|| unsafe {
+                puts(c"outer 1".as_ptr().cast());
+
+                let (_, inner) = lent_stdout()
+                    .unwrap()
+                    .capture_string(|| {
+                        puts(c"inner".as_ptr().cast());
+                    })
+                    .unwrap();
+                assert_eq!(inner, "inner\n");
+
+                puts(c"outer 2".as_ptr().cast());
+            })
+            .unwrap();
+
+        assert_eq!(outer, "outer 1\nouter 2\n");
+    }
+
+    #[test]
+    fn capture_to_memfd_and_path() {
+        let print = || unsafe {
+            for _ in 0..2048 {
+                puts(
+                    c"0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcde"
+                        .as_ptr()
+                        .cast(),
+                );
+            }
+        };
+        let lent = lent_stdout().unwrap();
+
+        let (_, mut memfd) = lent.capture_to_memfd(print).unwrap();
+        let mut captured = Vec::new();
+        memfd.read_to_end(&mut captured).unwrap();
+        assert_eq!(captured.len(), 2048 * 64);
+
+        let path = std::env::temp_dir().join(format!("wrcap-{}.out", std::process::id()));
+        let (_, mut file) = lent.capture_to_path(&path, print).unwrap();
+        let mut from_path = Vec::new();
+        file.read_to_end(&mut from_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(from_path, captured);
+    }
+
+    #[test]
+    fn capture_binary_output() {
+        let data = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe, b'\n'];
+        let write = || unsafe {
+            nix::libc::fwrite(data.as_ptr().cast(), 1, data.len(), stdout);
+        };
+        let lent = lent_stdout().unwrap();
+
+        assert_eq!(lent.capture_bytes(write).unwrap().1, data);
+        assert!(matches!(
+            lent.capture_string(write),
+            Err(CaptureError::Utf8(_))
+        ));
+        assert_eq!(
+            lent.capture_string_lossy(write).unwrap().1,
+            "\u{FFFD}PNG\0\u{FFFD}\u{FFFD}\n"
+        );
+    }
+
+    #[test]
+    fn capture_lines_with_timestamps() {
+        let (_, lines) = lent_stdout()
+            .unwrap()
+            .capture_lines(|| unsafe {
+                puts(c"first".as_ptr().cast());
+                nix::libc::fflush(stdout);
+
+                std::thread::sleep(std::time::Duration::from_millis(30));
+
+                printf(c"second\nthird".as_ptr().cast());
+            })
+            .unwrap();
+
+        let text: Vec<&[u8]> = lines.iter().map(|l| l.line.as_slice()).collect();
+        assert_eq!(text, [&b"first"[..], b"second", b"third"]);
+        assert!(lines[1].elapsed - lines[0].elapsed >= std::time::Duration::from_millis(30));
+    }
+
+    #[test]
+    fn buffering_during_capture() {
+        let original = unsafe { buffering::current(stdout) };
+
+        let (_, lines) = lent_stdout()
+            .unwrap()
+            .buffering(Buffering::Unbuffered)
+            .capture_lines(|| unsafe {
+                // no fflush needed to get separate timestamps
+                printf(c"first\n".as_ptr().cast());
+                std::thread::sleep(std::time::Duration::from_millis(30));
+                printf(c"second\n".as_ptr().cast());
+            })
+            .unwrap();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].elapsed - lines[0].elapsed >= std::time::Duration::from_millis(30));
+
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .buffering(Buffering::Full(64))
+            .capture_string(|| unsafe {
+                puts(c"fully buffered".as_ptr().cast());
+            })
+            .unwrap();
+        assert_eq!(r, "fully buffered\n");
+
+        assert_eq!(unsafe { buffering::current(stdout) }, original);
+    }
+
+    // without fops.c, a file without a buffer yet looks unbuffered
+    #[cfg(wrcap_fileno_swap)]
+    #[test]
+    fn buffering_of_other_files() {
+        // a file on a terminal that has not allocated its buffer yet
+        let (master, file) = unsafe {
+            let master = nix::libc::posix_openpt(nix::libc::O_RDWR | nix::libc::O_NOCTTY);
+            assert!(master >= 0);
+            assert_eq!(nix::libc::grantpt(master), 0);
+            assert_eq!(nix::libc::unlockpt(master), 0);
+
+            let slave = nix::libc::open(nix::libc::ptsname(master), nix::libc::O_RDWR);
+            assert!(slave >= 0);
+            let file = nix::libc::fdopen(slave, c"w".as_ptr());
+            assert!(!file.is_null());
+
+            (OwnedFd::from_raw_fd(master), file)
+        };
+        assert_eq!(unsafe { buffering::current(file) }, Buffering::Line(8192));
+
+        // its buffer could not be given back without leaking one per file
+        let lent = unsafe { lent_file(file) }.unwrap();
+        let lent = lent.buffering(Buffering::Full(64));
+        assert!(matches!(
+            lent.capture_string(|| unsafe { nix::libc::fputs(c"lost".as_ptr(), file) }),
+            Err(CaptureError::Install(_))
+        ));
+        assert_eq!(unsafe { buffering::current(file) }, Buffering::Line(8192));
+        drop(lent);
+
+        let (_, r) = unsafe { lent_file(file) }
+            .unwrap()
+            .capture_string(|| unsafe {
+                nix::libc::fputs(c"captured".as_ptr(), file);
+            })
+            .unwrap();
+        assert_eq!(r, "captured");
+
+        unsafe { nix::libc::fclose(file) };
+        drop(master);
+    }
+
+    #[test]
+    fn capture_child_processes() {
+        let (status, output) = lent_stdout()
+            .unwrap()
+            .capture_command({
+                let mut cmd = Command::new("sh");
+                cmd.args(["-c", "echo child"]);
+                cmd
+            })
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(output, b"child\n");
+
+        // keep the test harness from writing its own status lines into fd 1
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .redirect(Redirect::Descriptor)
+            .thread_policy(ThreadPolicy::SerializeWithStd)
+            .capture_string(|| unsafe {
+                nix::libc::system(c"echo from system".as_ptr());
+            })
+            .unwrap();
+        assert_eq!(r, "from system\n");
+    }
+
+    #[test]
+    fn thread_policy_serialize() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static C_DONE: AtomicBool = AtomicBool::new(false);
+        static RUST_DONE: AtomicBool = AtomicBool::new(false);
+
+        let mut others = Vec::new();
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .capture_string(|| unsafe {
+                others.push(std::thread::spawn(|| {
+                    printf(c"".as_ptr().cast());
+                    C_DONE.store(true, Ordering::SeqCst);
+                }));
+
+                // rust output is not held back
+                std::thread::spawn(|| io::stdout().flush().unwrap())
+                    .join()
+                    .unwrap();
+
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                puts(c"alone".as_ptr().cast());
+
+                assert!(!C_DONE.load(Ordering::SeqCst));
+            })
+            .unwrap();
+
+        assert_eq!(r, "alone\n");
+        for other in others {
+            other.join().unwrap();
+        }
+
+        let mut other = None;
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .thread_policy(ThreadPolicy::SerializeWithStd)
+            .capture_string(|| unsafe {
+                other = Some(std::thread::spawn(|| {
+                    io::stdout().flush().unwrap();
+                    RUST_DONE.store(true, Ordering::SeqCst);
+                }));
+
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                puts(c"alone".as_ptr().cast());
+
+                assert!(!RUST_DONE.load(Ordering::SeqCst));
+            })
+            .unwrap();
+
+        assert_eq!(r, "alone\n");
+        other.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn thread_policy_route_to_original() {
+        let lent = lent_stdout()
+            .unwrap()
+            .thread_policy(ThreadPolicy::RouteToOriginal);
+
+        let flush_elsewhere = || {
+            std::thread::spawn(|| io::stdout().flush().unwrap())
+                .join()
+                .unwrap();
+        };
+
+        if !cfg!(wrcap_fileno_swap) {
+            let r = lent.capture_bytes(flush_elsewhere);
+            assert!(matches!(r, Err(CaptureError::Install(_))));
+            return;
+        }
+
+        // rust output of other threads is not held back
+        lent.capture_bytes(flush_elsewhere).unwrap();
+
+        let lent = lent.redirect(Redirect::Descriptor);
+        let r = lent.capture_bytes(flush_elsewhere);
+        assert!(matches!(r, Err(CaptureError::Install(_))));
+    }
+
+    #[test]
+    fn thread_policy_include_all() {
+        let file = unsafe { nix::libc::tmpfile() };
+        assert!(!file.is_null());
+        let file_addr = file as usize;
+
+        let (_, r) = unsafe { lent_file(file) }
+            .unwrap()
+            .thread_policy(ThreadPolicy::IncludeAll)
+            .capture_string(|| {
+                std::thread::spawn(move || unsafe {
+                    let file = file_addr as *mut nix::libc::FILE;
+                    nix::libc::fputs(c"from another thread".as_ptr(), file);
+                    nix::libc::fflush(file);
+                })
+                .join()
+                .unwrap();
+            })
+            .unwrap();
+
+        assert_eq!(r, "from another thread");
+        unsafe { nix::libc::fclose(file) };
+    }
+
+    #[test]
+    fn capture_rust_and_c_output() {
+        // keep the test harness from writing its own status lines into fd 1
+        let (_, r) = lent_stdout()
+            .unwrap()
+            .redirect(Redirect::Descriptor)
+            .thread_policy(ThreadPolicy::SerializeWithStd)
+            .capture_string(|| unsafe {
+                puts(c"from c".as_ptr().cast());
+                nix::libc::fflush(stdout);
+
+                // no newline, so this stays in rust's buffer until restore
+                write!(io::stdout(), "from rust").unwrap();
+            })
+            .unwrap();
+
+        assert_eq!(r, "from c\nfrom rust");
+    }
+
+    #[test]
+    fn capture_arbitrary_file() {
+        let file = unsafe { nix::libc::tmpfile() };
+        assert!(!file.is_null());
+
+        let (_, r) = unsafe { lent_file(file) }
+            .unwrap()
+            .capture_string(|| unsafe {
+                nix::libc::fputs(c"to the log".as_ptr(), file);
+            })
+            .unwrap();
+
+        assert_eq!(r, "to the log");
+        unsafe { nix::libc::fclose(file) };
+    }
+
+    #[test]
+    fn feed_stdin() {
+        let mut lines = Vec::new();
+
+        lent_stdin()
+            .unwrap()
+            .feed_from(&b"hello\nworld\n"[..], || {
+                let mut buf = [0u8; 16];
+                while !unsafe { nix::libc::fgets(buf.as_mut_ptr().cast(), 16, stdin) }.is_null() {
+                    let line = std::ffi::CStr::from_bytes_until_nul(&buf).unwrap();
+                    lines.push(line.to_str().unwrap().to_owned());
+                }
+            })
+            .unwrap();
+
+        assert_eq!(lines, ["hello\n", "world\n"]);
+    }
+
+    #[test]
+    fn capture_stdio_interleaved() {
+        let lent = lent_stdio().unwrap();
+
+        let print = || unsafe {
+            printf(c"out 1\n".as_ptr().cast());
+            nix::libc::fflush(stdout);
+            nix::libc::fputs(c"err 1\n".as_ptr(), stderr);
+            printf(c"out 2\n".as_ptr().cast());
+            nix::libc::fflush(stdout);
+        };
+
+        let (_, merged) = lent.capture_merged(print).unwrap();
+        assert_eq!(merged, b"out 1\nerr 1\nout 2\n");
+
+        let (_, tagged) = lent.capture_tagged(print).unwrap();
+        let of = |stream| {
+            tagged
+                .iter()
+                .filter(|(s, _)| *s == stream)
+                .flat_map(|(_, bytes)| bytes.clone())
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(of(Stream::Stdout), b"out 1\nout 2\n");
+        assert_eq!(of(Stream::Stderr), b"err 1\n");
+    }
+
+    #[test]
+    fn capture_with_live_sink() {
+        struct Forward(std::sync::mpsc::Sender<Vec<u8>>);
+
+        impl Write for Forward {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.send(buf.to_vec()).unwrap();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        lent_stdout()
+            .unwrap()
+            .capture_with(Forward(tx), || unsafe {
+                puts(c"progress 1".as_ptr().cast());
+                nix::libc::fflush(stdout);
+
+                // seen by the sink before the closure returns
+                let live = rx.recv_timeout(std::time::Duration::from_secs(5));
+                assert_eq!(live.unwrap(), b"progress 1\n");
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn capture_tee_passes_through() {
+        use std::io::Seek;
+
+        let file = unsafe { nix::libc::tmpfile() };
+        assert!(!file.is_null());
+
+        let (_, r) = unsafe { lent_file(file) }
+            .unwrap()
+            .capture_tee(|| unsafe {
+                nix::libc::fputs(c"seen twice".as_ptr(), file);
+            })
+            .unwrap();
+        assert_eq!(r, b"seen twice");
+
+        let fd = unsafe { BorrowedFd::borrow_raw(nix::libc::fileno(file)) };
+        let mut original = File::from(fd.try_clone_to_owned().unwrap());
+        let mut passed = String::new();
+        original.rewind().unwrap();
+        original.read_to_string(&mut passed).unwrap();
+        assert_eq!(passed, "seen twice");
+
+        unsafe { nix::libc::fclose(file) };
+    }
+
+    #[test]
+    fn capture_session() {
+        fn step(lent: &LentFile, fail: bool) -> Result<()> {
+            let (_reader, writer) = pipe()?;
+            let session = lent.begin_capture_into(writer)?;
+            if fail {
+                // the session is dropped here and restores the file
+                return Err(CaptureError::Busy);
+            }
+
+            session.finish()
+        }
+
+        let lent = lent_stdout().unwrap();
+
+        let session = lent.begin_capture().unwrap();
+        unsafe { puts(c"first call".as_ptr().cast()) };
+        unsafe { puts(c"second call".as_ptr().cast()) };
+        let mut r = String::new();
+        session.finish().unwrap().read_to_string(&mut r).unwrap();
+        assert_eq!(r, "first call\nsecond call\n");
+
+        assert!(step(&lent, true).is_err());
+        let (_, r) = lent
+            .capture_string(|| unsafe {
+                puts(c"restored after drop".as_ptr().cast());
+            })
+            .unwrap();
+        assert_eq!(r, "restored after drop\n");
+    }
+
+    #[test]
+    fn capture_returns_closure_result() {
+        let lent = lent_stdout().unwrap();
+
+        let (n, r) = lent
+            .capture_string(|| unsafe { printf(c"status\n".as_ptr().cast()) })
+            .unwrap();
+        assert_eq!((n, r.as_str()), (7, "status\n"));
+
+        let parsed: Result<(i32, String)> = lent.try_capture_string(|| {
+            unsafe { puts(c"parsing".as_ptr().cast()) };
+            Ok(42)
+        });
+        assert_eq!(parsed.unwrap(), (42, "parsing\n".to_string()));
+
+        let failed: Result<((), String)> = lent.try_capture_string(|| Err(CaptureError::Busy));
+        assert!(matches!(failed, Err(CaptureError::Busy)));
+    }
+
+    #[test]
+    fn capture_with_limit() {
+        // far more than the pipe buffer, so the drain must keep reading
+        let print = || unsafe {
+            for i in 0..100_000 {
+                nix::libc::fprintf(stdout, c"%06d\n".as_ptr(), i);
+            }
+        };
+        let total = 100_000 * 7;
+
+        let lent = lent_stdout().unwrap().limit(14, Overflow::Truncate);
+        let (_, r) = lent.capture_string(print).unwrap();
+        assert_eq!(r, "000000\n000001\n");
+        assert_eq!(lent.dropped(), total - 14);
+
+        let lent = lent.limit(14, Overflow::KeepLast);
+        let (_, r) = lent.capture_string(print).unwrap();
+        assert_eq!(r, "099998\n099999\n");
+        assert_eq!(lent.dropped(), total - 14);
+
+        let lent = lent.limit(14, Overflow::Error);
+        let r = lent.capture_string(print);
+        assert!(matches!(
+            r,
+            Err(CaptureError::Overflow { limit: 14, dropped }) if dropped == total - 14
+        ));
+
+        // output within the limit is kept as is
+        let (_, r) = lent
+            .capture_string(|| unsafe { puts(c"short".as_ptr().cast()) })
+            .unwrap();
+        assert_eq!(r, "short\n");
+        assert_eq!(lent.dropped(), 0);
+    }
+
+    #[test]
+    fn capture_lines_and_stdio_with_limit() {
+        let print_out = || unsafe {
+            for i in 0..10_000 {
+                nix::libc::fprintf(stdout, c"%06d\n".as_ptr(), i);
+            }
+        };
+        let print = || unsafe {
+            for i in 0..10_000 {
+                nix::libc::fprintf(stdout, c"%06d\n".as_ptr(), i);
+                nix::libc::fflush(stdout);
+                nix::libc::fprintf(stderr, c"e%05d\n".as_ptr(), i);
+            }
+        };
+        let total = 10_000 * 14;
+
+        let lent = lent_stdout().unwrap().limit(10, Overflow::Truncate);
+        let (_, lines) = lent.capture_lines(print_out).unwrap();
+        let text: Vec<&[u8]> = lines.iter().map(|l| l.line.as_slice()).collect();
+        assert_eq!(text, [&b"000000"[..], b"000"]);
+        assert_eq!(lent.dropped(), total / 2 - 10);
+
+        let lent = lent.limit(10, Overflow::KeepLast);
+        let (_, lines) = lent.capture_lines(print_out).unwrap();
+        let text: Vec<&[u8]> = lines.iter().map(|l| l.line.as_slice()).collect();
+        assert_eq!(text, [&b"009999"[..]]);
+        assert_eq!(lent.dropped(), total / 2 - 7);
+
+        let lent = lent.limit(10, Overflow::Error);
+        assert!(matches!(
+            lent.capture_lines(print_out),
+            Err(CaptureError::Overflow { limit: 10, .. })
+        ));
+        drop(lent);
+
+        let lent = lent_stdio().unwrap().limit(10, Overflow::Truncate);
+        let (_, merged) = lent.capture_merged(print).unwrap();
+        assert_eq!(merged, b"000000\ne00");
+        assert_eq!(lent.dropped(), total - 10);
+
+        let (_, tagged) = lent.capture_tagged(print).unwrap();
+        let kept: usize = tagged.iter().map(|(_, bytes)| bytes.len()).sum();
+        assert_eq!(kept, 10);
+        // the pipes are read separately, so only the first chunk is known
+        assert_eq!(tagged[0].0, Stream::Stdout);
+        assert!(tagged[0].1.starts_with(b"000000\n"));
+        assert_eq!(lent.dropped(), total - 10);
+
+        let lent = lent.limit(14, Overflow::KeepLast);
+        let (_, merged) = lent.capture_merged(print).unwrap();
+        assert_eq!(merged, b"009999\ne09999\n");
+
+        let (_, tagged) = lent.capture_tagged(print).unwrap();
+        let kept: usize = tagged.iter().map(|(_, bytes)| bytes.len()).sum();
+        assert_eq!(kept, 14);
+        assert_eq!(tagged.last().unwrap().1.last(), Some(&b'\n'));
+        assert_eq!(lent.dropped(), total - 14);
+
+        let lent = lent.limit(10, Overflow::Error);
+        assert!(matches!(
+            lent.capture_merged(print),
+            Err(CaptureError::Overflow { limit: 10, .. })
+        ));
+        assert!(matches!(
+            lent.capture_tagged(print),
+            Err(CaptureError::Overflow { limit: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn silence_output() {
+        let lent = lent_stdout().unwrap();
+
+        let (n, r) = lent
+            .capture_string(|| {
+                unsafe { puts(c"before".as_ptr().cast()) };
+
+                // more than a pipe could hold
+                let n = lent
+                    .silence(|| unsafe {
+                        for _ in 0..100_000 {
+                            puts(c"noise".as_ptr().cast());
+                        }
+                        42
+                    })
+                    .unwrap();
+
+                unsafe { puts(c"after".as_ptr().cast()) };
+                n
+            })
+            .unwrap();
+
+        assert_eq!(n, 42);
+        assert_eq!(r, "before\nafter\n");
+    }
+
+    #[test]
+    fn capture_sessions_out_of_order() {
+        for redirect in [Redirect::Stream, Redirect::Descriptor] {
+            // keep the test harness from writing its own status lines into fd 1
+            let lent = lent_stdout()
+                .unwrap()
+                .redirect(redirect)
+                .thread_policy(ThreadPolicy::SerializeWithStd);
+
+            let outer = lent.begin_capture().unwrap();
+            unsafe { puts(c"outer".as_ptr().cast()) };
+            let inner = lent.begin_capture().unwrap();
+            unsafe { puts(c"inner".as_ptr().cast()) };
+
+            // the inner capture is still installed, so nothing is swapped yet
+            assert!(matches!(outer.finish(), Err(CaptureError::Restore(_))));
+            unsafe { puts(c"still inner".as_ptr().cast()) };
+
+            // ending the inner capture also restores the outer one
+            let mut r = String::new();
+            inner.finish().unwrap().read_to_string(&mut r).unwrap();
+            assert_eq!(r, "inner\nstill inner\n");
+
+            assert_ne!(unsafe { nix::libc::fcntl(1, nix::libc::F_GETFD) }, -1);
+            let (_, r) = lent
+                .capture_string(|| unsafe { puts(c"afterwards".as_ptr().cast()) })
+                .unwrap();
+            assert_eq!(r, "afterwards\n");
+        }
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    #[wrcap::capture]
+    fn capture_attribute() {
+        // other tests print to stdout, which is captured here as well
+        unsafe { nix::libc::fputs(c"from the test\n".as_ptr(), stderr) };
+        assert_eq!(test::stderr(), "from the test\n");
+
+        let n = assert_stderr!(
+            unsafe { nix::libc::fputs(c"asserted\n".as_ptr(), stderr) },
+            "asserted\n"
+        );
+        assert!(n >= 0);
+
+        // nested captures do not reach the test's own capture
+        assert_eq!(test::stderr(), "from the test\n");
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    #[wrcap::capture]
+    fn capture_attribute_with_worker_thread() {
+        std::thread::spawn(|| unsafe {
+            nix::libc::fputs(c"from a worker\n".as_ptr(), stderr);
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(test::stderr(), "from a worker\n");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "stderr does not match (- expected, + actual):\n  \"same\\n\"\n- \"old\\n\"\n+ \"new\\n\"\n"
+    )]
+    fn assert_output_diff() {
+        assert_stderr!(
+            unsafe {
+                nix::libc::fputs(c"same\nnew\n".as_ptr(), stderr);
+            },
+            "same\nold\n",
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_capture() {
+        let rt = ::tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+
+        let task = rt.spawn(async {
+            let lent = crate::tokio::lent_stdout().await.unwrap();
+
+            lent.capture_bytes(|| unsafe {
+                puts(c"from the blocking pool".as_ptr().cast());
+            })
+            .await
+            .unwrap()
+        });
+
+        assert_eq!(rt.block_on(task).unwrap(), b"from the blocking pool\n");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn tokio_lease_is_exclusive() {
+        // one blocking thread, so the lease and the probe share it
+        let rt = ::tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .max_blocking_threads(1)
+            .build()
+            .unwrap();
+
+        let r = rt.block_on(async {
+            let lent = crate::tokio::lent_stdout().await.unwrap();
+
+            let probe = ::tokio::task::spawn_blocking(|| try_lent_stdout().is_ok());
+            assert!(!probe.await.unwrap());
+
+            // the thread running the capture can still lend again
+            lent.capture_bytes(|| {
+                let (_, inner) = try_lent_stdout()
+                    .unwrap()
+                    .capture_bytes(|| unsafe { puts(c"nested".as_ptr().cast()) })
+                    .unwrap();
+                assert_eq!(inner, b"nested\n");
+            })
+            .await
+            .unwrap()
+        });
+
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::manual_c_str_literals)]
     fn stress_test() {
         let mut threads = Vec::new();
         for tid in 0..5 {
             let thread = std::thread::spawn(move || {
                 for i in 0..100 {
-                    let r = lent_stdout()
+                    let (_, r) = lent_stdout()
                         .unwrap()
                         .capture_string(|| unsafe {
                             puts(format!("Hello, world! {}\0", i).as_ptr());
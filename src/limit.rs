@@ -0,0 +1,99 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use crate::{CaptureError, LentFile, Result};
+
+/// What happens to output beyond the limit set with [`LentFile::limit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Keep the first bytes and discard the rest.
+    Truncate,
+    /// Keep only the last bytes, like a ring buffer.
+    KeepLast,
+    /// Discard the rest and fail with [`CaptureError::Overflow`] once the
+    /// closure returns.
+    Error,
+}
+
+impl LentFile {
+    /// Caps the output kept in memory by [`LentFile::capture_bytes`] and the
+    /// methods built on it, by [`LentFile::capture_tee`] and by
+    /// [`LentFile::capture_lines`], which drops whole lines with
+    /// [`Overflow::KeepLast`] where it can.
+    ///
+    /// The closure never blocks on the limit: output beyond it is still read
+    /// from the pipe and then dropped. [`LentFile::dropped`] reports how much.
+    pub fn limit(mut self, max: usize, overflow: Overflow) -> Self {
+        self.limit = Some((max, overflow));
+        self
+    }
+
+    /// Bytes dropped by the last limited capture.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    pub(crate) fn bounded(&self) -> Bounded {
+        Bounded {
+            limit: self.limit,
+            buf: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Takes the output out of `sink` and records what it dropped.
+    pub(crate) fn collect(&self, sink: Bounded) -> Result<Vec<u8>> {
+        self.settle(sink.dropped)?;
+        Ok(sink.buf.into())
+    }
+
+    /// Records what a limited sink dropped, and fails if the limit says so.
+    pub(crate) fn settle(&self, dropped: u64) -> Result<()> {
+        self.dropped.set(dropped);
+
+        match self.limit {
+            Some((limit, Overflow::Error)) if dropped > 0 => {
+                Err(CaptureError::Overflow { limit, dropped })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// In-memory sink that keeps at most the limit of its [`LentFile`].
+pub(crate) struct Bounded {
+    limit: Option<(usize, Overflow)>,
+    buf: VecDeque<u8>,
+    dropped: u64,
+}
+
+impl Write for Bounded {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self.limit {
+            None => self.buf.extend(data),
+            Some((max, Overflow::KeepLast)) => {
+                // only the tail of a large chunk can survive
+                let keep = &data[data.len().saturating_sub(max)..];
+                let excess = (self.buf.len() + keep.len()).saturating_sub(max);
+
+                self.buf.drain(..excess);
+                self.buf.extend(keep);
+                self.dropped += (excess + data.len() - keep.len()) as u64;
+            }
+            Some((max, Overflow::Truncate | Overflow::Error)) => {
+                let room = max.saturating_sub(self.buf.len()).min(data.len());
+
+                self.buf.extend(&data[..room]);
+                self.dropped += (data.len() - room) as u64;
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
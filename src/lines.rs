@@ -0,0 +1,119 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{LentFile, Overflow, Result};
+
+/// One line of captured output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedLine {
+    /// Time from the start of the capture until the first bytes of the line
+    /// were read from the pipe, i.e. when libc flushed them.
+    pub elapsed: Duration,
+    /// The line without its trailing newline.
+    pub line: Vec<u8>,
+}
+
+impl LentFile {
+    /// Captures output split into lines, each stamped with the time it arrived.
+    ///
+    /// Timestamps reflect when the file was flushed, so use an unbuffered or
+    /// line-buffered file to time individual lines. A last line without a
+    /// newline is included as well.
+    ///
+    /// The [`LentFile::limit`] counts line bytes including the newlines.
+    pub fn capture_lines<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, Vec<CapturedLine>)> {
+        let mut sink = Lines {
+            start: Instant::now(),
+            limit: self.limit,
+            kept: 0,
+            dropped: 0,
+            pending: None,
+            lines: VecDeque::new(),
+        };
+
+        let r = self.capture_with(&mut sink, f)?;
+        self.settle(sink.dropped)?;
+
+        if let Some(last) = sink.pending.take() {
+            sink.lines.push_back(last);
+        }
+
+        Ok((r, sink.lines.into()))
+    }
+}
+
+struct Lines {
+    start: Instant,
+    limit: Option<(usize, Overflow)>,
+    // bytes in lines and pending, newlines included
+    kept: usize,
+    dropped: u64,
+    pending: Option<CapturedLine>,
+    lines: VecDeque<CapturedLine>,
+}
+
+impl Lines {
+    /// Drops the oldest lines, or the head of a single line longer than
+    /// `max`, until at most `max` bytes are kept.
+    fn keep_last(&mut self, max: usize) {
+        while self.kept > max {
+            let excess = self.kept - max;
+
+            let removed = match self.lines.pop_front() {
+                Some(line) => line.line.len() + 1,
+                None => {
+                    let pending = self.pending.as_mut().expect("kept bytes without a line");
+                    pending.line.drain(..excess);
+                    excess
+                }
+            };
+
+            self.kept -= removed;
+            self.dropped += removed as u64;
+        }
+    }
+}
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let elapsed = self.start.elapsed();
+
+        let data = match self.limit {
+            Some((max, Overflow::Truncate | Overflow::Error)) => {
+                let room = max.saturating_sub(self.kept).min(buf.len());
+                self.dropped += (buf.len() - room) as u64;
+                &buf[..room]
+            }
+            _ => buf,
+        };
+        self.kept += data.len();
+
+        for chunk in data.split_inclusive(|&b| b == b'\n') {
+            let pending = self.pending.get_or_insert_with(|| CapturedLine {
+                elapsed,
+                line: Vec::new(),
+            });
+
+            match chunk.strip_suffix(b"\n") {
+                Some(rest) => {
+                    pending.line.extend_from_slice(rest);
+                    self.lines.extend(self.pending.take());
+                }
+                None => pending.line.extend_from_slice(chunk),
+            }
+        }
+
+        if let Some((max, Overflow::KeepLast)) = self.limit {
+            self.keep_last(max);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
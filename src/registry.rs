@@ -0,0 +1,126 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+/// Streams that are currently lent out, keyed by their `FILE*` address.
+struct Registry {
+    lent: Mutex<BTreeMap<usize, Holder>>,
+    released: Condvar,
+}
+
+/// The thread holding a stream, and how many of its leases are nested.
+///
+/// A detached lease has no owner until it is adopted, so no thread can nest
+/// into it.
+struct Holder {
+    owner: Option<ThreadId>,
+    depth: usize,
+}
+
+static REGISTRY: Registry = Registry {
+    lent: Mutex::new(BTreeMap::new()),
+    released: Condvar::new(),
+};
+
+impl Registry {
+    fn lent(&self) -> MutexGuard<'_, BTreeMap<usize, Holder>> {
+        // the map is updated atomically, so it is consistent even if poisoned
+        self.lent.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Whether `file` is held by a thread other than the current one.
+fn is_busy(lent: &BTreeMap<usize, Holder>, file: usize) -> bool {
+    lent.get(&file)
+        .is_some_and(|holder| holder.owner != Some(thread::current().id()))
+}
+
+fn enter(lent: &mut BTreeMap<usize, Holder>, file: usize, owner: Option<ThreadId>) -> Lease {
+    lent.entry(file).or_insert(Holder { owner, depth: 0 }).depth += 1;
+
+    Lease { file }
+}
+
+/// Exclusive right of one thread to lend one stream. Released on drop.
+///
+/// The holding thread can take further, nested leases for the same stream.
+pub(crate) struct Lease {
+    file: usize,
+}
+
+/// Blocks until no other thread holds a lease for `file`.
+pub(crate) fn acquire(file: *mut nix::libc::FILE) -> Lease {
+    let file = file as usize;
+
+    let mut lent = REGISTRY.lent();
+    while is_busy(&lent, file) {
+        lent = REGISTRY
+            .released
+            .wait(lent)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    enter(&mut lent, file, Some(thread::current().id()))
+}
+
+/// Blocks until no thread at all holds a lease for `file`, and takes one that
+/// belongs to no thread until [`Lease::adopt`] is called.
+#[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+pub(crate) fn acquire_detached(file: *mut nix::libc::FILE) -> Lease {
+    let file = file as usize;
+
+    let mut lent = REGISTRY.lent();
+    while lent.contains_key(&file) {
+        lent = REGISTRY
+            .released
+            .wait(lent)
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    enter(&mut lent, file, None)
+}
+
+/// Like [`acquire`], but gives up once `timeout` has passed. A zero timeout
+/// only checks once.
+pub(crate) fn acquire_timeout(file: *mut nix::libc::FILE, timeout: Duration) -> Option<Lease> {
+    let file = file as usize;
+
+    let (mut lent, waited) = REGISTRY
+        .released
+        .wait_timeout_while(REGISTRY.lent(), timeout, |lent| is_busy(lent, file))
+        .unwrap_or_else(PoisonError::into_inner);
+    if waited.timed_out() {
+        return None;
+    }
+
+    Some(enter(&mut lent, file, Some(thread::current().id())))
+}
+
+impl Lease {
+    /// Makes the current thread the holder of a detached lease.
+    #[cfg_attr(not(feature = "tokio"), allow(dead_code))]
+    pub(crate) fn adopt(&self) {
+        if let Some(holder) = REGISTRY.lent().get_mut(&self.file) {
+            debug_assert!(holder.owner.is_none(), "lease already has an owner");
+            holder.owner = Some(thread::current().id());
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut lent = REGISTRY.lent();
+
+        let holder = lent.get_mut(&self.file).expect("lease not registered");
+        holder.depth -= 1;
+        if holder.depth == 0 {
+            lent.remove(&self.file);
+            drop(lent);
+
+            REGISTRY.released.notify_all();
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    io::{self, PipeReader, Read, pipe},
+    os::fd::{AsRawFd, IntoRawFd},
+    thread,
+};
+
+use crate::{
+    Buffering, CaptureError, LentFile, Overflow, Redirect, Result, ThreadPolicy, drain,
+    lent_stderr, lent_stdout,
+};
+
+/// Which standard stream a captured chunk came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Output of [`LentStdio::capture_tagged`], in arrival order.
+pub type Chunks = Vec<(Stream, Vec<u8>)>;
+
+/// `stdout` and `stderr` lent together.
+pub struct LentStdio {
+    stdout: LentFile,
+    stderr: LentFile,
+}
+
+/// Lends both `stdout` and `stderr`.
+///
+/// The locks are always taken stdout first, so concurrent `lent_stdio` calls
+/// cannot deadlock each other. Code that lends the streams one by one must use
+/// the same order.
+pub fn lent_stdio() -> Result<LentStdio> {
+    let stdout = lent_stdout()?;
+    let stderr = lent_stderr()?;
+
+    Ok(LentStdio { stdout, stderr })
+}
+
+impl LentStdio {
+    pub fn redirect(self, redirect: Redirect) -> Self {
+        LentStdio {
+            stdout: self.stdout.redirect(redirect),
+            stderr: self.stderr.redirect(redirect),
+        }
+    }
+
+    pub fn buffering(self, buffering: Buffering) -> Self {
+        LentStdio {
+            stdout: self.stdout.buffering(buffering),
+            stderr: self.stderr.buffering(buffering),
+        }
+    }
+
+    pub fn thread_policy(self, policy: ThreadPolicy) -> Self {
+        LentStdio {
+            stdout: self.stdout.thread_policy(policy),
+            stderr: self.stderr.thread_policy(policy),
+        }
+    }
+
+    /// Caps the output kept by [`LentStdio::capture_merged`] and
+    /// [`LentStdio::capture_tagged`], counted over both streams.
+    pub fn limit(self, max: usize, overflow: Overflow) -> Self {
+        LentStdio {
+            stdout: self.stdout.limit(max, overflow),
+            stderr: self.stderr.limit(max, overflow),
+        }
+    }
+
+    /// Bytes dropped by the last limited capture.
+    pub fn dropped(&self) -> u64 {
+        self.stdout.dropped()
+    }
+
+    pub(crate) fn capture_into<OUT: IntoRawFd, ERR: IntoRawFd, R, F: FnOnce() -> R>(
+        &self,
+        out: OUT,
+        err: ERR,
+        f: F,
+    ) -> Result<R> {
+        let out = unsafe { self.stdout.install(out)? };
+        let err = unsafe { self.stderr.install(err)? };
+
+        let r = f();
+
+        // restore in reverse order, and both even if one fails
+        let err_restored = err.restore();
+        let out_restored = out.restore();
+
+        err_restored.and(out_restored)?;
+        Ok(r)
+    }
+
+    /// Captures both streams into a single transcript.
+    ///
+    /// Both files write to the same pipe, so the transcript has the order in
+    /// which the files were flushed. `stdout` is still buffered by libc.
+    pub fn capture_merged<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, Vec<u8>)> {
+        let (reader, writer) = pipe().map_err(CaptureError::Pipe)?;
+        let writer_err = writer.try_clone().map_err(CaptureError::Pipe)?;
+
+        let mut sink = self.stdout.bounded();
+        let r = thread::scope(|scope| {
+            let drain = scope.spawn(|| drain(reader, &mut sink));
+
+            let captured = self.capture_into(writer, writer_err, f);
+            let drained = drain.join().expect("drain thread panicked");
+
+            let r = captured?;
+            drained?;
+            Ok::<_, CaptureError>(r)
+        })?;
+
+        Ok((r, self.stdout.collect(sink)?))
+    }
+
+    /// Captures both streams as chunks tagged with their origin.
+    ///
+    /// Consecutive output of the same stream is merged into one chunk. The
+    /// streams use separate pipes, so output that arrives in both pipes before
+    /// the drain wakes up is ordered stdout first.
+    pub fn capture_tagged<R, F: FnOnce() -> R>(&self, f: F) -> Result<(R, Chunks)> {
+        let (out_reader, out_writer) = pipe().map_err(CaptureError::Pipe)?;
+        let (err_reader, err_writer) = pipe().map_err(CaptureError::Pipe)?;
+
+        let mut chunks = Tagged {
+            limit: self.stdout.limit,
+            chunks: VecDeque::new(),
+            kept: 0,
+            dropped: 0,
+        };
+        let r = thread::scope(|scope| {
+            let drain = scope.spawn(|| drain_tagged(out_reader, err_reader, &mut chunks));
+
+            let captured = self.capture_into(out_writer, err_writer, f);
+            let drained = drain.join().expect("drain thread panicked");
+
+            let r = captured?;
+            drained?;
+            Ok::<_, CaptureError>(r)
+        })?;
+        self.stdout.settle(chunks.dropped)?;
+
+        Ok((r, chunks.chunks.into()))
+    }
+}
+
+/// Tagged chunks, keeping at most the limit of the [`LentStdio`].
+struct Tagged {
+    limit: Option<(usize, Overflow)>,
+    chunks: VecDeque<(Stream, Vec<u8>)>,
+    kept: usize,
+    dropped: u64,
+}
+
+impl Tagged {
+    fn push(&mut self, stream: Stream, data: &[u8]) {
+        let data = match self.limit {
+            None => data,
+            Some((max, Overflow::KeepLast)) => {
+                // only the tail of a large chunk can survive
+                let keep = &data[data.len().saturating_sub(max)..];
+                self.dropped += (data.len() - keep.len()) as u64;
+                keep
+            }
+            Some((max, Overflow::Truncate | Overflow::Error)) => {
+                let room = max.saturating_sub(self.kept).min(data.len());
+                self.dropped += (data.len() - room) as u64;
+                &data[..room]
+            }
+        };
+        if data.is_empty() {
+            return;
+        }
+
+        match self.chunks.back_mut() {
+            Some((last, bytes)) if *last == stream => bytes.extend_from_slice(data),
+            _ => self.chunks.push_back((stream, data.to_vec())),
+        }
+        self.kept += data.len();
+
+        if let Some((max, Overflow::KeepLast)) = self.limit {
+            while self.kept > max {
+                let excess = self.kept - max;
+                let (_, front) = self.chunks.front_mut().expect("kept bytes without a chunk");
+
+                let removed = if front.len() <= excess {
+                    self.chunks.pop_front().map_or(0, |(_, bytes)| bytes.len())
+                } else {
+                    front.drain(..excess);
+                    excess
+                };
+
+                self.kept -= removed;
+                self.dropped += removed as u64;
+            }
+        }
+    }
+}
+
+fn drain_tagged(out: PipeReader, err: PipeReader, chunks: &mut Tagged) -> io::Result<()> {
+    let mut readers = [(Stream::Stdout, Some(out)), (Stream::Stderr, Some(err))];
+    let mut buf = [0u8; 8192];
+
+    while readers.iter().any(|(_, reader)| reader.is_some()) {
+        let mut fds = readers.each_ref().map(|(_, reader)| nix::libc::pollfd {
+            // negative fds are ignored by poll
+            fd: reader.as_ref().map_or(-1, |r| r.as_raw_fd()),
+            events: nix::libc::POLLIN,
+            revents: 0,
+        });
+
+        if unsafe { nix::libc::poll(fds.as_mut_ptr(), fds.len() as _, -1) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for ((stream, reader), fd) in readers.iter_mut().zip(fds) {
+            let Some(r) = reader.as_mut() else { continue };
+            if fd.revents == 0 {
+                continue;
+            }
+
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                // the writer is closed once the file is restored
+                *reader = None;
+                continue;
+            }
+
+            chunks.push(*stream, &buf[..n]);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Helpers for testing code that prints through C stdio.
+//!
+//! A test marked with `#[wrcap::capture]` runs with `stdout` and `stderr`
+//! captured, so its output does not mix with the test harness output, and
+//! [`stdout()`] and [`stderr()`] return what it printed so far. If the test
+//! panics, the captured output is printed through Rust's std streams, where
+//! the harness shows it next to the failure.
+//!
+//! The capture uses [`ThreadPolicy::IncludeAll`], so threads the test spawns
+//! can print and their output is captured too. They must not lend `stdout`
+//! or `stderr` themselves, since the test thread holds both for the whole
+//! test.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::fs::FileExt,
+    },
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{CaptureError, LentFile, Result, ThreadPolicy, cvt, lent_stdio};
+
+struct Session {
+    stdout: File,
+    stderr: File,
+}
+
+thread_local! {
+    static SESSION: RefCell<Option<Session>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with `stdout` and `stderr` captured. This is what
+/// `#[wrcap::capture]` expands to.
+pub fn run<R>(f: impl FnOnce() -> R) -> R {
+    let session = Session {
+        stdout: anonymous_file().expect("failed to create capture file"),
+        stderr: anonymous_file().expect("failed to create capture file"),
+    };
+    let out = session
+        .stdout
+        .try_clone()
+        .expect("failed to clone capture file");
+    let err = session
+        .stderr
+        .try_clone()
+        .expect("failed to clone capture file");
+
+    let stdio = lent_stdio()
+        .expect("failed to lend stdio")
+        .thread_policy(ThreadPolicy::IncludeAll);
+    let previous = SESSION.replace(Some(session));
+
+    let result = stdio.capture_into(out, err, || panic::catch_unwind(AssertUnwindSafe(f)));
+    drop(stdio);
+
+    let session = SESSION.replace(previous).expect("capture session vanished");
+
+    match result.expect("failed to capture stdio") {
+        Ok(value) => value,
+        Err(payload) => {
+            print!("{}", String::from_utf8_lossy(&read_all(&session.stdout)));
+            eprint!("{}", String::from_utf8_lossy(&read_all(&session.stderr)));
+
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+/// What the current test wrote to `stdout` so far.
+///
+/// # Panics
+///
+/// Outside of a `#[wrcap::capture]` test.
+pub fn stdout() -> String {
+    unsafe { nix::libc::fflush(crate::stdout) };
+    read_session(|session| &session.stdout)
+}
+
+/// What the current test wrote to `stderr` so far.
+///
+/// # Panics
+///
+/// Outside of a `#[wrcap::capture]` test.
+pub fn stderr() -> String {
+    unsafe { nix::libc::fflush(crate::stderr) };
+    read_session(|session| &session.stderr)
+}
+
+fn read_session(file: impl FnOnce(&Session) -> &File) -> String {
+    SESSION.with_borrow(|session| {
+        let session = session
+            .as_ref()
+            .expect("not inside a #[wrcap::capture] test");
+
+        String::from_utf8_lossy(&read_all(file(session))).into_owned()
+    })
+}
+
+// the capture keeps writing at the shared offset, so read without moving it
+fn read_all(file: &File) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 8192];
+
+    loop {
+        match file.read_at(&mut chunk, buf.len() as u64) {
+            Ok(0) => return buf,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => panic!("failed to read captured output: {e}"),
+        }
+    }
+}
+
+fn anonymous_file() -> io::Result<File> {
+    let file = unsafe { nix::libc::tmpfile() };
+    if file.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    // keep only the descriptor
+    let fd = cvt(unsafe { nix::libc::dup(nix::libc::fileno(file)) });
+    unsafe { nix::libc::fclose(file) };
+
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd?) }))
+}
+
+/// Backs [`assert_stdout!`](crate::assert_stdout) and
+/// [`assert_stderr!`](crate::assert_stderr).
+#[doc(hidden)]
+#[track_caller]
+pub fn assert_output<R>(
+    lent: Result<LentFile>,
+    name: &str,
+    expected: &str,
+    f: impl FnOnce() -> R,
+) -> R {
+    let lent = lent.unwrap_or_else(|e| panic!("failed to lend {name}: {e}"));
+
+    let (result, actual) = lent
+        .capture_string_lossy(f)
+        .unwrap_or_else(|e: CaptureError| panic!("failed to capture {name}: {e}"));
+
+    if actual != expected {
+        panic!(
+            "{name} does not match (- expected, + actual):\n{}",
+            diff(expected, &actual)
+        );
+    }
+
+    result
+}
+
+/// A line diff of `expected` against `actual`. Lines are quoted so that
+/// whitespace and missing newlines are visible.
+fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.split_inclusive('\n').collect();
+    let new: Vec<&str> = actual.split_inclusive('\n').collect();
+
+    // lcs[i][j]: longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            out += &format!("  {:?}\n", old[i]);
+            (i, j) = (i + 1, j + 1);
+        } else if j == new.len() || (i < old.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out += &format!("- {:?}\n", old[i]);
+            i += 1;
+        } else {
+            out += &format!("+ {:?}\n", new[j]);
+            j += 1;
+        }
+    }
+
+    out
+}
+
+/// Asserts that `expr` writes exactly `expected` to C `stdout`, and evaluates
+/// to the value of `expr`. On mismatch, the panic message contains a line
+/// diff.
+///
+/// ```ignore
+/// let n = wrcap::assert_stdout!(unsafe { puts(c"hi".as_ptr()) }, "hi\n");
+/// ```
+#[macro_export]
+macro_rules! assert_stdout {
+    ($expr:expr, $expected:expr $(,)?) => {
+        $crate::test::assert_output($crate::lent_stdout(), "stdout", $expected, || $expr)
+    };
+}
+
+/// Like [`assert_stdout!`], for C `stderr`.
+#[macro_export]
+macro_rules! assert_stderr {
+    ($expr:expr, $expected:expr $(,)?) => {
+        $crate::test::assert_output($crate::lent_stderr(), "stderr", $expected, || $expr)
+    };
+}
@@ -0,0 +1,155 @@
+//! Async counterparts of the lending functions for tokio.
+//!
+//! Waiting for a stream and running the capture both happen on the blocking
+//! thread pool, so no executor thread is blocked.
+
+use std::{
+    io,
+    os::fd::OwnedFd,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use ::tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    net::unix::pipe::Receiver,
+    task::{JoinHandle, spawn_blocking},
+};
+
+use crate::{
+    Buffering, CaptureError, LentFile, Redirect, Result, ThreadPolicy, registry, registry::Lease,
+};
+
+/// A stream lent to an async task.
+///
+/// Unlike [`LentFile`], this does not hold `flockfile`, which belongs to a
+/// single thread. The file is locked by the blocking thread running the
+/// capture.
+pub struct AsyncLentFile {
+    file: usize,
+    redirect: Redirect,
+    buffering: Option<Buffering>,
+    policy: ThreadPolicy,
+    lease: Lease,
+}
+
+pub async fn lent_stdout() -> Result<AsyncLentFile> {
+    unsafe { lent_file(crate::stdout) }.await // SAFETY: stdout lives as long as the process
+}
+
+pub async fn lent_stderr() -> Result<AsyncLentFile> {
+    unsafe { lent_file(crate::stderr) }.await // SAFETY: stderr lives as long as the process
+}
+
+/// Async version of [`crate::lent_file`].
+///
+/// # Safety
+///
+/// `file` must be an open stream that is not closed while the returned
+/// [`AsyncLentFile`] or a capture started from it is alive.
+pub unsafe fn lent_file(
+    file: *mut nix::libc::FILE,
+) -> impl Future<Output = Result<AsyncLentFile>> + Send {
+    // the pointer itself is not Send
+    let file = file as usize;
+
+    async move {
+        let lease =
+            spawn_blocking(move || registry::acquire_detached(file as *mut nix::libc::FILE))
+                .await
+                .expect("lease task panicked");
+
+        Ok(AsyncLentFile {
+            file,
+            redirect: Redirect::default(),
+            buffering: None,
+            policy: ThreadPolicy::default(),
+            lease,
+        })
+    }
+}
+
+impl AsyncLentFile {
+    pub fn redirect(mut self, redirect: Redirect) -> Self {
+        self.redirect = redirect;
+        self
+    }
+
+    pub fn buffering(mut self, buffering: Buffering) -> Self {
+        self.buffering = Some(buffering);
+        self
+    }
+
+    pub fn thread_policy(mut self, policy: ThreadPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Runs `f` on the blocking thread pool and returns a reader over its
+    /// output, which can be read while `f` is still running.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn capture<F: FnOnce() + Send + 'static>(self, f: F) -> Result<Capture> {
+        let (reader, writer) = std::io::pipe().map_err(CaptureError::Pipe)?;
+        let reader = Receiver::from_owned_fd(OwnedFd::from(reader)).map_err(CaptureError::Pipe)?;
+
+        let task = spawn_blocking(move || {
+            let AsyncLentFile {
+                file,
+                redirect,
+                buffering,
+                policy,
+                lease,
+            } = self;
+
+            // nested lends inside f happen on this thread
+            lease.adopt();
+
+            let mut lent = unsafe { LentFile::from_lease(file as *mut nix::libc::FILE, lease) };
+            lent.redirect = redirect;
+            lent.buffering = buffering;
+            lent.thread_policy(policy).capture_into(writer, f)
+        });
+
+        Ok(Capture { reader, task })
+    }
+
+    pub async fn capture_bytes<F: FnOnce() + Send + 'static>(self, f: F) -> Result<Vec<u8>> {
+        let mut capture = self.capture(f)?;
+
+        let mut buf = Vec::new();
+        capture.read_to_end(&mut buf).await?;
+        capture.finish().await?;
+
+        Ok(buf)
+    }
+}
+
+/// Output of a running capture. Reaches end of file once the original
+/// descriptor is restored.
+pub struct Capture {
+    reader: Receiver,
+    task: JoinHandle<Result<()>>,
+}
+
+impl Capture {
+    /// Waits for the capture to finish and returns its result. A panic in the
+    /// closure is resumed here.
+    pub async fn finish(self) -> Result<()> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(CaptureError::Io(io::Error::other(e))),
+        }
+    }
+}
+
+impl AsyncRead for Capture {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}